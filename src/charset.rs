@@ -0,0 +1,130 @@
+//! Detection of the character encoding of downloaded documents, to decode them to UTF-8.
+
+use std::borrow::Cow;
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use reqwest::header::HeaderValue;
+
+/// Like browsers, only the start of a document is searched for its declared encoding
+const PRESCAN_LENGTH: usize = 1024;
+
+lazy_static! {
+    static ref CHARSET_REGEX: Regex =
+        Regex::new(r#"(?i)charset\s*=\s*["']?\s*([\w.:-]+)"#).unwrap();
+    static ref META_REGEX: Regex =
+        Regex::new(r#"(?i)<meta\s[^>]*charset\s*=\s*["']?\s*([\w.:-]+)"#).unwrap();
+    static ref XML_DECLARATION_REGEX: Regex =
+        Regex::new(r#"^\s*<\?xml\s[^>]*encoding\s*=\s*["']([\w.:-]+)["']"#).unwrap();
+}
+
+fn prescan(body: &[u8]) -> &[u8] {
+    &body[..body.len().min(PRESCAN_LENGTH)]
+}
+
+/// Get the encoding from the `charset` parameter of a `Content-Type` header
+fn from_content_type(content_type: Option<&HeaderValue>) -> Option<&'static Encoding> {
+    let content_type = content_type?.as_bytes();
+    let label = CHARSET_REGEX.captures(content_type)?.get(1)?;
+    Encoding::for_label(label.as_bytes())
+}
+
+/// Get the encoding declared by a `<meta>` element of an HTML document
+fn from_meta(body: &[u8]) -> Option<&'static Encoding> {
+    let label = META_REGEX.captures(prescan(body))?.get(1)?;
+    // a document declaring a UTF-16 encoding in ASCII can't actually be UTF-16
+    Encoding::for_label(label.as_bytes()).map(|e| e.output_encoding())
+}
+
+/// Guess the encoding from the content of the document
+fn sniff(body: &[u8]) -> &'static Encoding {
+    if std::str::from_utf8(body).is_ok() {
+        return UTF_8;
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(body, true);
+    detector.guess(None, true)
+}
+
+/// Decode an HTML document to UTF-8. The encoding is determined, in order of precedence,
+/// by a byte order mark, the `Content-Type` header of the response,
+/// the `<meta charset>` of the document, and finally by guessing from its content.
+pub fn decode_html(body: &[u8], content_type: Option<&HeaderValue>) -> String {
+    let encoding = Encoding::for_bom(body)
+        .map(|(e, _)| e)
+        .or_else(|| from_content_type(content_type))
+        .or_else(|| from_meta(body))
+        .unwrap_or_else(|| sniff(body));
+
+    let (html, _) = encoding.decode_with_bom_removal(body);
+    html.into_owned()
+}
+
+/// Decode a feed to UTF-8, unless the feed parser is able to decode it by itself:
+/// XML feeds declaring their encoding are left alone, as are feeds with a byte order mark.
+/// Otherwise, the encoding is determined by the `Content-Type` header of the response,
+/// and finally by guessing from its content.
+pub fn decode_feed<'a>(body: &'a [u8], content_type: Option<&HeaderValue>) -> Cow<'a, [u8]> {
+    if Encoding::for_bom(body).is_some() || XML_DECLARATION_REGEX.is_match(prescan(body)) {
+        return Cow::Borrowed(body);
+    }
+
+    let encoding = from_content_type(content_type).unwrap_or_else(|| sniff(body));
+    if encoding == UTF_8 {
+        return Cow::Borrowed(body);
+    }
+
+    match encoding.decode_without_bom_handling(body) {
+        (Cow::Borrowed(feed), _) => Cow::Borrowed(feed.as_bytes()),
+        (Cow::Owned(feed), _) => Cow::Owned(feed.into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "café" in windows-1252
+    const CAFE_1252: &[u8] = b"caf\xe9";
+
+    #[test]
+    fn html_from_content_type() {
+        let content_type = HeaderValue::from_static("text/html; charset=windows-1252");
+        let html = decode_html(CAFE_1252, Some(&content_type));
+        assert_eq!(html, "café");
+    }
+
+    #[test]
+    fn html_from_meta() {
+        let body = b"<html><head><meta charset=\"iso-8859-1\"></head><body>caf\xe9</body></html>";
+        assert!(decode_html(body, None).contains("café"));
+    }
+
+    #[test]
+    fn html_bom_wins() {
+        let content_type = HeaderValue::from_static("text/html; charset=windows-1252");
+        let html = decode_html("\u{feff}café".as_bytes(), Some(&content_type));
+        assert_eq!(html, "café");
+    }
+
+    #[test]
+    fn html_utf8_is_sniffed() {
+        assert_eq!(decode_html("café".as_bytes(), None), "café");
+    }
+
+    #[test]
+    fn feed_declaring_encoding_is_left_alone() {
+        let body = b"<?xml version=\"1.0\" encoding=\"windows-1252\"?><rss>caf\xe9</rss>";
+        assert!(matches!(decode_feed(body, None), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn feed_from_content_type() {
+        let content_type = HeaderValue::from_static("application/rss+xml; charset=windows-1252");
+        let feed = decode_feed(CAFE_1252, Some(&content_type));
+        assert_eq!(feed.as_ref(), "café".as_bytes());
+    }
+}
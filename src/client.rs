@@ -1,61 +1,660 @@
 use std::{
     cmp::min,
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Seek, Write},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::Duration,
 };
 
-use anyhow::{anyhow, Result};
-use bytes::Bytes;
+use anyhow::{anyhow, Context, Result};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
 use reqwest::{
-    header::{HeaderValue, CONTENT_TYPE},
-    IntoUrl,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, RETRY_AFTER,
+    },
+    Certificate, ClientBuilder, IntoUrl, Proxy, Request, RequestBuilder, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{self, Semaphore},
+    time::{self, Instant},
 };
-use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use url::Origin;
+
+use crate::{cookies::Jar, settings::Instance};
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+const DEFAULT_READ_TIMEOUT: u64 = 60;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: u64 = 1;
+const DEFAULT_HOST_REQUESTS: usize = 2;
+const DEFAULT_HOST_DELAY: u64 = 0;
+const DEFAULT_MAX_FEED_SIZE: usize = 10 * 1024;
+const DEFAULT_MAX_ARTICLE_SIZE: usize = 10 * 1024;
+const DEFAULT_MAX_IMAGE_SIZE: usize = 5 * 1024;
+/// The longest a server can ask us to wait with a `Retry-After` header
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// The longest to wait before retrying a request, however many times it was retried
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
 
 pub struct Client {
     client: Arc<reqwest::Client>,
+    user_agent: Arc<String>,
+    certificates: Arc<Vec<Certificate>>,
     semaphore: Arc<Semaphore>,
-    sigterm: Arc<AtomicBool>,
+    hosts: Arc<Mutex<HashMap<String, Arc<Host>>>>,
+    jars: Arc<Mutex<HashMap<PathBuf, Arc<Jar>>>>,
+    shutdown: CancellationToken,
+    options: Arc<Options>,
+    auth: Option<Arc<Auth>>,
+}
+
+/// How requests are made by a [Client]
+struct Options {
+    read_timeout: Duration,
+    max_retries: u32,
+    retry_delay: Duration,
+    host_requests: usize,
+    host_delay: Duration,
+    /// Maximum size of a feed, in bytes
+    max_feed_size: usize,
+    /// Maximum size of an article, in bytes
+    max_article_size: usize,
+    /// Maximum size of an image, in bytes
+    max_image_size: usize,
+}
+
+/// The kinds of resources requested, each with their own size limit
+#[derive(Clone, Copy, Debug)]
+pub enum Resource {
+    Feed,
+    Article,
+    Image,
+}
+
+/// Authentication added to the requests of an instance
+struct Auth {
+    basic: Option<(String, Option<String>)>,
+    bearer: Option<String>,
+    headers: HeaderMap,
+    /// Whether to authenticate the requests for the entries of the feed, not just the feed itself
+    entries: bool,
+    /// Origin of the feed, the only one the authentication is sent to,
+    /// so it isn't leaked to the other sites the entries link to
+    origin: Option<Origin>,
+}
+
+/// Limits the requests made to a single host. Hosts are shared between instances, so the lowest
+/// number of concurrent requests and the longest delay of the instances requesting a host apply.
+struct Host {
+    semaphore: Semaphore,
+    limits: Mutex<HostLimits>,
+    /// Permits to forget as they are released, as the limit was lowered while they were in use
+    excess: AtomicUsize,
+    /// When the next request to the host may start
+    next_request: sync::Mutex<Instant>,
+}
+
+struct HostLimits {
+    requests: usize,
+    delay: Duration,
+}
+
+impl Host {
+    fn new(requests: usize, delay: Duration) -> Host {
+        let requests = min(requests.max(1), Semaphore::MAX_PERMITS);
+        Host {
+            semaphore: Semaphore::new(requests),
+            limits: Mutex::new(HostLimits { requests, delay }),
+            excess: AtomicUsize::new(0),
+            next_request: sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Lower the limits of the host to those of another instance, if they are stricter
+    fn restrict(&self, requests: usize, delay: Duration) {
+        let mut limits = self.limits.lock().unwrap_or_else(|e| e.into_inner());
+        let requests = requests.max(1);
+        if requests < limits.requests {
+            let excess = limits.requests - requests;
+            let forgotten = self.semaphore.forget_permits(excess);
+            self.excess.fetch_add(excess - forgotten, Ordering::SeqCst);
+            limits.requests = requests;
+        }
+        limits.delay = limits.delay.max(delay);
+    }
+
+    fn delay(&self) -> Duration {
+        self.limits.lock().unwrap_or_else(|e| e.into_inner()).delay
+    }
+
+    /// Wait until a request can be made to the host
+    async fn acquire(&self) -> Result<sync::SemaphorePermit<'_>, sync::AcquireError> {
+        loop {
+            let permit = self.semaphore.acquire().await?;
+            let excess = self
+                .excess
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if excess.is_err() {
+                return Ok(permit);
+            }
+            permit.forget();
+        }
+    }
 }
 
-pub struct Response {
+pub struct Response<B = Bytes> {
     pub content_type: Option<HeaderValue>,
-    pub body: Bytes,
+    pub validators: Validators,
+    /// The URL the response came from, after following redirects
+    pub url: Url,
+    pub body: B,
+}
+
+/// Where the body of a response is written as it is received
+trait Sink {
+    fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()>;
+    /// Discard anything written by a previous attempt at the request
+    fn reset(&mut self) -> io::Result<()>;
+}
+
+impl Sink for BytesMut {
+    fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.clear();
+        Ok(())
+    }
+}
+
+impl Sink for File {
+    fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.write_all(chunk)
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.set_len(0)?;
+        self.rewind()
+    }
+}
+
+/// HTTP cache validators of a response, used to make conditional requests
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Validators {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Why a single attempt at a request failed
+enum Failure {
+    /// The request may succeed if it is tried again, optionally after the given delay
+    Transient(anyhow::Error, Option<Duration>),
+    Fatal(anyhow::Error),
+}
+
+impl From<reqwest::Error> for Failure {
+    fn from(err: reqwest::Error) -> Self {
+        // TLS, redirect and decoding errors would just fail again
+        if err.is_timeout() || err.is_connect() {
+            Failure::Transient(err.into(), None)
+        } else {
+            Failure::Fatal(err.into())
+        }
+    }
+}
+
+impl From<time::error::Elapsed> for Failure {
+    fn from(err: time::error::Elapsed) -> Self {
+        Failure::Transient(err.into(), None)
+    }
+}
+
+/// Start building a [reqwest::Client] which trusts the extra `certificates`
+fn builder(user_agent: &str, certificates: &[Certificate]) -> ClientBuilder {
+    certificates.iter().fold(
+        reqwest::Client::builder().user_agent(user_agent),
+        |builder, certificate| builder.add_root_certificate(certificate.clone()),
+    )
+}
+
+/// Parse the `Retry-After` header, which is either a number of seconds or an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = if let Ok(seconds) = value.parse::<u64>() {
+        Duration::from_secs(seconds)
+    } else {
+        let date = DateTime::parse_from_rfc2822(value).ok()?;
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default()
+    };
+
+    Some(min(delay, MAX_RETRY_AFTER))
+}
+
+/// How long to wait before the next retry of a request which was already retried `retries`
+/// times, doubling `retry_delay` with each retry up to [MAX_BACKOFF]
+fn backoff(retry_delay: Duration, retries: u32) -> Duration {
+    2u32.checked_pow(retries)
+        .and_then(|factor| retry_delay.checked_mul(factor))
+        .map_or(MAX_BACKOFF, |delay| min(delay, MAX_BACKOFF))
+}
+
+/// Load the certificates of each PEM file in `paths`
+fn load_certificates(paths: &[PathBuf]) -> Result<Vec<Certificate>> {
+    let mut certificates = Vec::new();
+    for path in paths {
+        let pem = fs::read(path)
+            .with_context(|| format!("can't read certificates from {}", path.display()))?;
+        let bundle = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("can't parse certificates from {}", path.display()))?;
+        certificates.extend(bundle);
+    }
+
+    Ok(certificates)
 }
 
 impl Client {
-    pub fn new(user_agent: String, concurrent_requests: usize) -> Result<Client> {
+    pub fn new(
+        user_agent: String,
+        concurrent_requests: usize,
+        ca_certificates: &[PathBuf],
+        shutdown: CancellationToken,
+    ) -> Result<Client> {
         let semaphore = Semaphore::new(min(concurrent_requests, Semaphore::MAX_PERMITS));
-        let sigterm = Arc::new(AtomicBool::new(false));
-        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&sigterm))?;
+        let certificates = load_certificates(ca_certificates)?;
         Ok(Client {
-            client: Arc::new(reqwest::Client::builder().user_agent(user_agent).build()?),
+            client: Arc::new(
+                builder(&user_agent, &certificates)
+                    .connect_timeout(Duration::from_secs(DEFAULT_CONNECT_TIMEOUT))
+                    .build()?,
+            ),
+            user_agent: Arc::new(user_agent),
+            certificates: Arc::new(certificates),
             semaphore: Arc::new(semaphore),
-            sigterm,
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+            jars: Arc::new(Mutex::new(HashMap::new())),
+            shutdown,
+            options: Arc::new(Options {
+                read_timeout: Duration::from_secs(DEFAULT_READ_TIMEOUT),
+                max_retries: DEFAULT_MAX_RETRIES,
+                retry_delay: Duration::from_secs(DEFAULT_RETRY_DELAY),
+                host_requests: DEFAULT_HOST_REQUESTS,
+                host_delay: Duration::from_millis(DEFAULT_HOST_DELAY),
+                max_feed_size: DEFAULT_MAX_FEED_SIZE * 1024,
+                max_article_size: DEFAULT_MAX_ARTICLE_SIZE * 1024,
+                max_image_size: DEFAULT_MAX_IMAGE_SIZE * 1024,
+            }),
+            auth: None,
         })
     }
 
-    pub async fn get<U: IntoUrl>(&self, url: U) -> Result<Response> {
-        let permit = self.semaphore.acquire().await?;
-        if self.sigterm.load(Ordering::Relaxed) {
-            return Err(anyhow!("SIGTERM"));
+    /// Create a client which makes its requests according to the settings of `instance`.
+    /// It shares its request limits with `self`.
+    pub fn for_instance(&self, instance: &Instance) -> Result<Client> {
+        let settings = &instance.requests;
+        let connect_timeout = settings.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let user_agent = instance.user_agent.as_ref().unwrap_or(&self.user_agent);
+        let mut builder = builder(user_agent, &self.certificates)
+            .connect_timeout(Duration::from_secs(connect_timeout))
+            .danger_accept_invalid_certs(instance.accept_invalid_certs);
+        match settings.proxy.as_deref() {
+            None => (),
+            Some("") => builder = builder.no_proxy(),
+            Some(proxy) => {
+                builder = builder
+                    .proxy(Proxy::all(proxy).with_context(|| format!("invalid proxy {}", proxy))?)
+            }
         }
+        if let Some(cookies) = &instance.cookies {
+            builder = builder.cookie_provider(self.jar(cookies)?);
+        }
+        let client = builder.build()?;
 
-        let res = self.client.get(url).send().await?;
-        if self.sigterm.load(Ordering::Relaxed) {
-            return Err(anyhow!("SIGTERM"));
+        let mut headers = HeaderMap::with_capacity(instance.headers.len());
+        for (name, value) in &instance.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
         }
+        let auth = if instance.basic_auth.is_some()
+            || instance.bearer_token.is_some()
+            || !headers.is_empty()
+        {
+            Some(Arc::new(Auth {
+                basic: instance
+                    .basic_auth
+                    .as_ref()
+                    .map(|b| (b.username.clone(), b.password.clone())),
+                bearer: instance.bearer_token.clone(),
+                headers,
+                entries: instance.authenticate_entries,
+                origin: Url::parse(&instance.url).ok().map(|url| url.origin()),
+            }))
+        } else {
+            None
+        };
 
-        let content_type = res.headers().get(CONTENT_TYPE).cloned();
-        let body = res.bytes().await?;
-        if self.sigterm.load(Ordering::Relaxed) {
-            return Err(anyhow!("SIGTERM"));
+        Ok(Client {
+            client: Arc::new(client),
+            user_agent: Arc::clone(&self.user_agent),
+            certificates: Arc::clone(&self.certificates),
+            semaphore: Arc::clone(&self.semaphore),
+            hosts: Arc::clone(&self.hosts),
+            jars: Arc::clone(&self.jars),
+            shutdown: self.shutdown.clone(),
+            options: Arc::new(Options {
+                read_timeout: Duration::from_secs(
+                    settings.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT),
+                ),
+                max_retries: settings.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+                retry_delay: Duration::from_secs(
+                    settings.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY),
+                ),
+                host_requests: settings.host_requests.unwrap_or(DEFAULT_HOST_REQUESTS),
+                host_delay: Duration::from_millis(
+                    settings.host_delay.unwrap_or(DEFAULT_HOST_DELAY),
+                ),
+                // huge sizes from the settings just mean no limit
+                max_feed_size: settings
+                    .max_feed_size
+                    .unwrap_or(DEFAULT_MAX_FEED_SIZE)
+                    .saturating_mul(1024),
+                max_article_size: settings
+                    .max_article_size
+                    .unwrap_or(DEFAULT_MAX_ARTICLE_SIZE)
+                    .saturating_mul(1024),
+                max_image_size: settings
+                    .max_image_size
+                    .unwrap_or(DEFAULT_MAX_IMAGE_SIZE)
+                    .saturating_mul(1024),
+            }),
+            auth,
+        })
+    }
+
+    /// Get the client to use for the requests of the entries of a feed, which only keeps the
+    /// authentication of the instance if it is configured to
+    pub fn for_entries(&self) -> Client {
+        let mut client = self.clone();
+        if client.auth.as_ref().is_some_and(|auth| !auth.entries) {
+            client.auth = None;
+        }
+        client
+    }
+
+    /// Create a GET request to `url`, authenticated if need be
+    fn request<U: IntoUrl>(&self, url: U) -> Result<Request> {
+        let req = self.client.get(url).build()?;
+        let Some(auth) = self
+            .auth
+            .as_ref()
+            .filter(|auth| auth.origin.as_ref() == Some(&req.url().origin()))
+        else {
+            return Ok(req);
+        };
+
+        let mut req = RequestBuilder::from_parts(reqwest::Client::clone(&self.client), req);
+        if let Some((username, password)) = &auth.basic {
+            req = req.basic_auth(username, password.as_ref());
+        }
+        if let Some(token) = &auth.bearer {
+            req = req.bearer_auth(token);
+        }
+        Ok(req.headers(auth.headers.clone()).build()?)
+    }
+
+    /// The maximum size of a `resource`, in bytes
+    pub fn max_size(&self, resource: Resource) -> usize {
+        match resource {
+            Resource::Feed => self.options.max_feed_size,
+            Resource::Article => self.options.max_article_size,
+            Resource::Image => self.options.max_image_size,
+        }
+    }
+
+    pub async fn get<U: IntoUrl>(&self, url: U, resource: Resource) -> Result<Response> {
+        let res = self
+            .send(self.request(url)?, resource, BytesMut::new())
+            .await?
+            .ok_or_else(|| anyhow!("unexpected {}", StatusCode::NOT_MODIFIED))?;
+        Ok(Response {
+            content_type: res.content_type,
+            validators: res.validators,
+            url: res.url,
+            body: res.body.freeze(),
+        })
+    }
+
+    /// Like [Client::get], but streams the body to an anonymous temporary file in `dir`
+    /// instead of keeping it in memory. The file is rewound, ready to be read.
+    pub async fn download<U: IntoUrl, P: AsRef<Path>>(
+        &self,
+        url: U,
+        resource: Resource,
+        dir: P,
+    ) -> Result<Response<File>> {
+        let file = tempfile::tempfile_in(dir)?;
+        let mut res = self
+            .send(self.request(url)?, resource, file)
+            .await?
+            .ok_or_else(|| anyhow!("unexpected {}", StatusCode::NOT_MODIFIED))?;
+        res.body.rewind()?;
+        Ok(res)
+    }
+
+    /// Like [Client::get] for a feed, but sends the given `validators` along with the request.
+    /// Returns `None` if the server responds that the feed has not been modified.
+    pub async fn get_conditional<U: IntoUrl>(
+        &self,
+        url: U,
+        validators: &Validators,
+    ) -> Result<Option<Response>> {
+        let mut req = self.request(url)?;
+        let headers = req.headers_mut();
+        if let Some(etag) = &validators.etag {
+            headers.insert(IF_NONE_MATCH, HeaderValue::from_str(etag)?);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(last_modified)?);
+        }
+
+        let res = self.send(req, Resource::Feed, BytesMut::new()).await?;
+        Ok(res.map(|res| Response {
+            content_type: res.content_type,
+            validators: res.validators,
+            url: res.url,
+            body: res.body.freeze(),
+        }))
+    }
+
+    /// Get the limits for requests made to `host`, creating them if this is its first request,
+    /// or applying those of this instance if they are stricter
+    fn host(&self, host: &str) -> Arc<Host> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let (requests, delay) = (self.options.host_requests, self.options.host_delay);
+        match hosts.get(host) {
+            Some(host) => {
+                host.restrict(requests, delay);
+                Arc::clone(host)
+            }
+            None => {
+                let created = Arc::new(Host::new(requests, delay));
+                hosts.insert(host.to_owned(), Arc::clone(&created));
+                created
+            }
+        }
+    }
+
+    /// Get the cookie jar for the cookies.txt file at `path`, loading it if this is its first use.
+    /// Instances using the same file share the jar, so none of their cookies are lost when saving.
+    fn jar(&self, path: &Path) -> Result<Arc<Jar>> {
+        let mut jars = self.jars.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(jar) = jars.get(path) {
+            return Ok(Arc::clone(jar));
+        }
+
+        let jar = Arc::new(Jar::load(path)?);
+        jars.insert(path.to_path_buf(), Arc::clone(&jar));
+        Ok(jar)
+    }
+
+    /// Wait until the hook is asked to exit
+    pub async fn cancelled(&self) {
+        self.shutdown.cancelled().await
+    }
+
+    /// Send the request, retrying with an exponential backoff on transient failures.
+    /// The request is aborted as soon as the hook is asked to exit.
+    async fn send<S: Sink>(
+        &self,
+        req: Request,
+        resource: Resource,
+        mut body: S,
+    ) -> Result<Option<Response<S>>> {
+        let mut retries = 0;
+        loop {
+            let attempt = req
+                .try_clone()
+                .ok_or_else(|| anyhow!("request cannot be cloned"))?;
+            body.reset()?;
+            let res = tokio::select! {
+                _ = self.shutdown.cancelled() => return Err(anyhow!("cancelled")),
+                res = self.attempt(attempt, resource, &mut body) => res,
+            };
+            let delay = match res {
+                Ok(res) => {
+                    return Ok(res.map(|res| Response {
+                        content_type: res.content_type,
+                        validators: res.validators,
+                        url: res.url,
+                        body,
+                    }))
+                }
+                Err(Failure::Fatal(err)) => return Err(err),
+                Err(Failure::Transient(err, _)) if retries >= self.options.max_retries => {
+                    return Err(err)
+                }
+                Err(Failure::Transient(err, delay)) => {
+                    eprintln!("feed: retrying: {:?}", err);
+                    delay.unwrap_or_else(|| backoff(self.options.retry_delay, retries))
+                }
+            };
+
+            retries += 1;
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return Err(anyhow!("cancelled")),
+                _ = time::sleep(delay) => (),
+            }
+        }
+    }
+
+    /// Make a single attempt at the request, writing the body of the response to `body`.
+    /// Returns the response without its body,
+    /// or `None` if the resource has not been modified.
+    async fn attempt<S: Sink>(
+        &self,
+        req: Request,
+        resource: Resource,
+        body: &mut S,
+    ) -> Result<Option<Response<()>>, Failure> {
+        let host = self.host(req.url().host_str().unwrap_or_default());
+        let host_permit = host.acquire().await.map_err(|e| Failure::Fatal(e.into()))?;
+        {
+            let mut next_request = host.next_request.lock().await;
+            time::sleep_until(*next_request).await;
+            *next_request = Instant::now() + host.delay();
+        }
+
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .map_err(|e| Failure::Fatal(e.into()))?;
+
+        let mut res = time::timeout(self.options.read_timeout, self.client.execute(req)).await??;
+
+        let status = res.status();
+        if status == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            return Err(Failure::Transient(
+                anyhow!("{} from {}", status, res.url()),
+                retry_after(res.headers()),
+            ));
+        }
+        if !status.is_success() {
+            return Err(Failure::Fatal(anyhow!("{} from {}", status, res.url())));
+        }
+
+        let headers = res.headers();
+        let content_type = headers.get(CONTENT_TYPE).cloned();
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+
+        let max_size = self.max_size(resource);
+        let too_large = |res: &reqwest::Response| {
+            Failure::Fatal(anyhow!(
+                "{:?} {} is larger than the maximum of {} KiB",
+                resource,
+                res.url(),
+                max_size / 1024
+            ))
+        };
+        if res
+            .content_length()
+            .is_some_and(|len| len > max_size as u64)
+        {
+            return Err(too_large(&res));
+        }
+
+        let mut size = 0;
+        while let Some(chunk) = time::timeout(self.options.read_timeout, res.chunk()).await?? {
+            size += chunk.len();
+            if size > max_size {
+                return Err(too_large(&res));
+            }
+            body.write_chunk(&chunk)
+                .map_err(|e| Failure::Fatal(e.into()))?;
         }
 
         drop(permit);
-        Ok(Response { content_type, body })
+        drop(host_permit);
+        Ok(Some(Response {
+            content_type,
+            validators,
+            url: res.url().clone(),
+            body: (),
+        }))
     }
 }
 
@@ -63,8 +662,149 @@ impl Clone for Client {
     fn clone(&self) -> Self {
         Self {
             client: Arc::clone(&self.client),
+            user_agent: Arc::clone(&self.user_agent),
+            certificates: Arc::clone(&self.certificates),
             semaphore: Arc::clone(&self.semaphore),
-            sigterm: Arc::clone(&self.sigterm),
+            hosts: Arc::clone(&self.hosts),
+            jars: Arc::clone(&self.jars),
+            shutdown: self.shutdown.clone(),
+            options: Arc::clone(&self.options),
+            auth: self.auth.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::RequestSettings;
+
+    /// A client for an instance whose feed is at `url`, authenticated with a token and a header
+    fn authenticated(url: &str, authenticate_entries: bool) -> Client {
+        let client = Client::new(String::new(), 1, &[], CancellationToken::new()).unwrap();
+        let instance = Instance {
+            url: url.to_owned(),
+            bearer_token: Some("token".to_owned()),
+            headers: HashMap::from([("X-Api-Key".to_owned(), "key".to_owned())]),
+            authenticate_entries,
+            ..Instance::default()
+        };
+        client.for_instance(&instance).unwrap()
+    }
+
+    fn is_authenticated(client: &Client, url: &str) -> bool {
+        let req = client.request(url).unwrap();
+        let headers = req.headers();
+        assert_eq!(
+            headers.contains_key("authorization"),
+            headers.contains_key("x-api-key")
+        );
+        headers.contains_key("authorization")
+    }
+
+    #[test]
+    fn authentication_is_only_sent_to_the_origin_of_the_feed() {
+        let client = authenticated("https://example.com/feed.xml", true);
+        assert!(is_authenticated(&client, "https://example.com/feed.xml"));
+        let entries = client.for_entries();
+        assert!(is_authenticated(&entries, "https://example.com/article"));
+        assert!(!is_authenticated(
+            &entries,
+            "https://cdn.example.com/image.png"
+        ));
+        assert!(!is_authenticated(&entries, "http://example.com/article"));
+        assert!(!is_authenticated(
+            &entries,
+            "https://example.com:8443/article"
+        ));
+    }
+
+    #[test]
+    fn authentication_of_entries_is_opt_in() {
+        let client = authenticated("https://example.com/feed.xml", false);
+        assert!(is_authenticated(&client, "https://example.com/feed.xml"));
+        assert!(!is_authenticated(
+            &client.for_entries(),
+            "https://example.com/article"
+        ));
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(retry_after(&headers("5")), Some(Duration::from_secs(5)));
+        assert_eq!(retry_after(&headers("3600")), Some(MAX_RETRY_AFTER));
+    }
+
+    #[test]
+    fn retry_after_date() {
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        let date = (Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+        assert_eq!(retry_after(&headers(&date)), Some(MAX_RETRY_AFTER));
+    }
+
+    #[test]
+    fn retry_after_invalid() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers("soon")), None);
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let delay = Duration::from_secs(1);
+        assert_eq!(backoff(delay, 0), Duration::from_secs(1));
+        assert_eq!(backoff(delay, 1), Duration::from_secs(2));
+        assert_eq!(backoff(delay, 3), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff(Duration::from_secs(1), 20), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::from_secs(1), 40), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::MAX, 1), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn hosts_apply_the_strictest_limits_of_the_instances() {
+        let client = Client::new(String::new(), 10, &[], CancellationToken::new()).unwrap();
+        let limited = |host_requests, host_delay| {
+            let instance = Instance {
+                requests: RequestSettings {
+                    host_requests: Some(host_requests),
+                    host_delay: Some(host_delay),
+                    ..RequestSettings::default()
+                },
+                ..Instance::default()
+            };
+            client.for_instance(&instance).unwrap()
+        };
+        let (first, second, third) = (limited(3, 100), limited(1, 0), limited(2, 500));
+
+        let host = first.host("example.com");
+        let permit = host.acquire().await.unwrap();
+        assert_eq!(host.semaphore.available_permits(), 2);
+        assert_eq!(host.delay(), Duration::from_millis(100));
+
+        // the permit in use is only forgotten once it is released
+        second.host("example.com");
+        assert_eq!(host.semaphore.available_permits(), 0);
+        assert_eq!(host.delay(), Duration::from_millis(100));
+        drop(permit);
+        let permit = host.acquire().await.unwrap();
+        assert_eq!(host.semaphore.available_permits(), 0);
+        drop(permit);
+        assert_eq!(host.semaphore.available_permits(), 1);
+
+        third.host("example.com");
+        assert_eq!(host.semaphore.available_permits(), 1);
+        assert_eq!(host.delay(), Duration::from_millis(500));
+    }
+}
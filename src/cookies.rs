@@ -0,0 +1,250 @@
+//! A cookie jar backed by a Netscape cookies.txt file, like those exported by desktop browsers.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use cookie_store::{CookieDomain, CookieExpiration, CookieStore, RawCookie};
+use reqwest::header::HeaderValue;
+use url::Url;
+
+use crate::persist::write_atomic;
+
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+pub struct Jar {
+    path: PathBuf,
+    store: RwLock<CookieStore>,
+    /// Whether the store changed since it was loaded, and needs to be saved
+    changed: AtomicBool,
+}
+
+/// Convert a line of a cookies.txt file to a `Set-Cookie` header value and the URL it could
+/// have been received from. Returns `None` if the cookie has expired.
+/// The line has seven tab-separated fields:
+/// domain, include subdomains, path, secure, expiry (UNIX time, 0 for session), name and value.
+fn parse_line(line: &str) -> Result<Option<(String, Url)>> {
+    let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+
+    let fields = line.split('\t').collect::<Vec<_>>();
+    let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+        return Err(anyhow!("expected 7 fields, found {}", fields.len()));
+    };
+
+    let host = domain.trim_start_matches('.');
+    let secure = secure.eq_ignore_ascii_case("TRUE");
+    let scheme = if secure { "https" } else { "http" };
+    let url = Url::parse(&format!("{scheme}://{host}{path}"))?;
+
+    let mut cookie = format!("{name}={value}; Path={path}");
+    if subdomains.eq_ignore_ascii_case("TRUE") {
+        write!(cookie, "; Domain={host}")?;
+    }
+    let expires = expires.parse::<i64>()?;
+    if expires > 0 {
+        let max_age = expires.saturating_sub(chrono::Utc::now().timestamp());
+        // browsers export their expired cookies too
+        if max_age <= 0 {
+            return Ok(None);
+        }
+        write!(cookie, "; Max-Age={max_age}")?;
+    }
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+
+    Ok(Some((cookie, url)))
+}
+
+impl Jar {
+    /// Load the cookies from the cookies.txt file at `path`.
+    /// A missing file is treated as empty, and created when the jar is saved.
+    pub fn load(path: &Path) -> Result<Jar> {
+        let mut store = CookieStore::default();
+        if path.exists() {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("can't read cookies from {}", path.display()))?;
+            for (i, line) in content.lines().enumerate() {
+                let line = line.trim_end_matches('\r');
+                if line.trim().is_empty()
+                    || (line.starts_with('#') && !line.starts_with(HTTP_ONLY_PREFIX))
+                {
+                    continue;
+                }
+
+                let res = parse_line(line).and_then(|cookie| {
+                    if let Some((cookie, url)) = cookie {
+                        store.parse(&cookie, &url).map_err(|e| anyhow!(e))?;
+                    }
+                    Ok(())
+                });
+                if let Err(err) = res {
+                    eprintln!(
+                        "feed: {:?}",
+                        err.context(format!("line {} of {}", i + 1, path.display()))
+                    );
+                }
+            }
+        }
+
+        Ok(Jar {
+            path: path.to_path_buf(),
+            store: RwLock::new(store),
+            changed: AtomicBool::new(false),
+        })
+    }
+
+    /// Write the cookies back to the cookies.txt file they were loaded from
+    fn save(&self) -> Result<()> {
+        let store = self.store.read().unwrap_or_else(|e| e.into_inner());
+        write_atomic(&self.path, |writer| {
+            writeln!(writer, "# Netscape HTTP Cookie File")?;
+            for cookie in store.iter_unexpired() {
+                let (domain, subdomains) = match &cookie.domain {
+                    CookieDomain::HostOnly(domain) => (domain.to_owned(), "FALSE"),
+                    CookieDomain::Suffix(domain) => (format!(".{domain}"), "TRUE"),
+                    CookieDomain::NotPresent | CookieDomain::Empty => continue,
+                };
+                let expires = match &cookie.expires {
+                    CookieExpiration::AtUtc(time) => time.unix_timestamp(),
+                    CookieExpiration::SessionEnd => 0,
+                };
+                let prefix = if cookie.http_only().unwrap_or(false) {
+                    HTTP_ONLY_PREFIX
+                } else {
+                    ""
+                };
+                let secure = if cookie.secure().unwrap_or(false) {
+                    "TRUE"
+                } else {
+                    "FALSE"
+                };
+
+                writeln!(
+                    writer,
+                    "{prefix}{domain}\t{subdomains}\t{}\t{secure}\t{expires}\t{}\t{}",
+                    cookie.path.as_ref(),
+                    cookie.name(),
+                    cookie.value()
+                )?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl reqwest::cookie::CookieStore for Jar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| RawCookie::parse(v.to_owned()).ok())
+            .collect::<Vec<_>>();
+        if cookies.is_empty() {
+            return;
+        }
+
+        let mut store = self.store.write().unwrap_or_else(|e| e.into_inner());
+        store.store_response_cookies(cookies.into_iter(), url);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let store = self.store.read().unwrap_or_else(|e| e.into_inner());
+        let cookies = store
+            .get_request_values(url)
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>();
+        if cookies.is_empty() {
+            return None;
+        }
+
+        HeaderValue::from_str(&cookies.join("; ")).ok()
+    }
+}
+
+impl Drop for Jar {
+    fn drop(&mut self) {
+        if !self.changed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Err(err) = self.save() {
+            eprintln!(
+                "feed: {:?}",
+                err.context(format!("saving cookies to {}", self.path.display()))
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_session_cookie() {
+        let (cookie, url) = parse_line("example.com\tFALSE\t/feeds\tFALSE\t0\tsession\tabc")
+            .unwrap()
+            .unwrap();
+        assert_eq!(cookie, "session=abc; Path=/feeds");
+        assert_eq!(url.as_str(), "http://example.com/feeds");
+    }
+
+    #[test]
+    fn parse_secure_http_only_cookie() {
+        let expires = chrono::Utc::now().timestamp() + 3600;
+        let line = format!("#HttpOnly_.example.com\tTRUE\t/\tTRUE\t{expires}\ttoken\txyz");
+        let (cookie, url) = parse_line(&line).unwrap().unwrap();
+        assert!(cookie.starts_with("token=xyz; Path=/; Domain=example.com; Max-Age="));
+        assert!(cookie.ends_with("; Secure; HttpOnly"));
+        assert_eq!(url.as_str(), "https://example.com/");
+    }
+
+    #[test]
+    fn skip_expired_cookies() {
+        let line = "#HttpOnly_.example.com\tTRUE\t/\tTRUE\t1\ttoken\txyz";
+        assert!(parse_line(line).unwrap().is_none());
+    }
+
+    #[test]
+    fn load_skips_expired_cookies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.txt");
+        let expires = chrono::Utc::now().timestamp() + 3600;
+        fs::write(
+            &path,
+            format!(
+                "# Netscape HTTP Cookie File\n\
+                example.com\tFALSE\t/\tFALSE\t1\texpired\told\n\
+                example.com\tFALSE\t/\tFALSE\t{expires}\tvalid\tnew\n"
+            ),
+        )
+        .unwrap();
+
+        let jar = Jar::load(&path).unwrap();
+        let url = Url::parse("http://example.com/").unwrap();
+        let cookies = reqwest::cookie::CookieStore::cookies(&jar, &url).unwrap();
+        assert_eq!(cookies, "valid=new");
+    }
+
+    #[test]
+    fn parse_invalid_lines() {
+        assert!(parse_line("example.com\tFALSE\t/\tFALSE\t0\tname").is_err());
+        assert!(parse_line("example.com\tFALSE\t/\tFALSE\tnever\tname\tvalue").is_err());
+    }
+}
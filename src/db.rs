@@ -1,116 +1,952 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt::Display,
     fs::File,
     future::Future,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
 
-use ::anyhow::Result;
+use ::anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_json::Serializer;
-use tokio::sync::Mutex;
+use serde_json::{Serializer, Value};
+
+use crate::{
+    client::Validators,
+    persist::write_atomic,
+    plato::notify,
+    settings::{RetentionSettings, UpdatePolicy},
+};
 
 const DB_PATH: &str = "db.json";
+/// Extension of a copy of the last db.json which could be read, to recover from if db.json gets
+/// corrupted
+const BACKUP_EXTENSION: &str = "json.bak";
+/// How often the Db is saved while feeds are downloaded, so little is lost if the hook is killed
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+/// Version of the layout of db.json, to be incremented with every change which older versions of
+/// the hook can't read. Older layouts are migrated when loaded.
+const VERSION: u64 = 2;
+
+/// The document saved for an entry
+pub struct Document {
+    pub path: PathBuf,
+    pub title: String,
+    pub link: Option<String>,
+    /// Title of the feed the entry is from
+    pub feed: String,
+    /// SHA-256 of the content of the entry
+    pub content_hash: String,
+    /// Size of the document, in bytes
+    pub size: u64,
+}
+
+/// An entry whose document is saved
+pub struct Record {
+    pub id: String,
+    pub path: PathBuf,
+    pub title: Option<String>,
+    /// Title of the feed the entry is from
+    pub feed: Option<String>,
+    /// Number of the entry in the series of its feed
+    pub number: Option<u64>,
+    pub last_update: DateTime<Utc>,
+}
+
+/// The document previously saved for an entry
+pub struct Saved {
+    pub path: PathBuf,
+    /// SHA-256 of the content of the entry, if it is known
+    pub content_hash: Option<String>,
+}
 
 #[derive(Clone, Deserialize, Default, Serialize)]
+#[serde(default)]
 struct Entry {
-    path: PathBuf,
+    /// Where the document of the entry was saved; `None` if it never was
+    path: Option<PathBuf>,
+    /// When the entry was last updated, according to the feed
     last_update: DateTime<Utc>,
+    title: Option<String>,
+    link: Option<String>,
+    /// Title of the feed the entry is from
+    feed: Option<String>,
+    /// SHA-256 of the content of the entry
+    content_hash: Option<String>,
+    /// Size of the document, in bytes
+    size: Option<u64>,
+    /// Number of the entry in the series of its feed, given when it is first saved
+    number: Option<u64>,
+    /// When the document was last saved
+    fetched: Option<DateTime<Utc>>,
+    /// Number of times saving the entry failed since it was last saved
+    failures: u32,
+    last_error: Option<String>,
+    /// When the document was removed by the retention policy. The entry is kept until it is no
+    /// longer in the feed, so it isn't downloaded again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Deserialize, Default, Serialize)]
+#[serde(default)]
+struct Feed {
+    /// The URL the feed was last downloaded from
+    url: String,
+    /// When the feed was last downloaded successfully
+    last_fetch: Option<DateTime<Utc>>,
+    /// HTTP cache validators of the feed at [Feed::url]
+    #[serde(skip_serializing_if = "Validators::is_empty")]
+    validators: Validators,
+    /// The URL the feed was redirected to, if it was
+    redirect: Option<String>,
+    /// The number last given to an entry of the feed, so every entry gets a greater one
+    number: u64,
+    /// Entries of the feed, keyed by their id
+    entries: HashMap<String, Entry>,
+    /// Whether the entries of the feed were listed during this run
+    #[serde(skip)]
+    listed: bool,
 }
 
-#[derive(Deserialize, Default, Serialize)]
+impl Feed {
+    /// Copy of the feed, without any of its entries
+    fn metadata(&self) -> Feed {
+        Feed {
+            url: self.url.clone(),
+            last_fetch: self.last_fetch,
+            validators: self.validators.clone(),
+            redirect: self.redirect.clone(),
+            number: self.number,
+            entries: HashMap::new(),
+            listed: false,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Default, Serialize)]
 struct JsonDatabase {
+    version: u64,
+    /// Feeds keyed by the path of their server
+    feeds: HashMap<String, Feed>,
+    /// Entries saved before they were namespaced by feed, keyed by their id only.
+    /// They are moved to their feed by the first feed having an entry with their id.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    legacy: HashMap<String, Entry>,
+}
+
+/// Layout of db.json before it was versioned
+#[derive(Deserialize)]
+struct Unversioned {
+    /// Entries keyed by their id only
+    #[serde(default)]
     feeds: HashMap<String, Entry>,
+    /// Entries keyed by the path of their feed's server, then by their id
+    #[serde(default)]
+    entries: HashMap<String, HashMap<String, Entry>>,
+}
+
+impl From<Unversioned> for JsonDatabase {
+    fn from(db: Unversioned) -> Self {
+        // validators used to be keyed by URL, so they are dropped;
+        // each feed is just downloaded in full once
+        let feeds = db
+            .entries
+            .into_iter()
+            .map(|(path, entries)| {
+                let feed = Feed {
+                    entries,
+                    ..Feed::default()
+                };
+                (path, feed)
+            })
+            .collect();
+
+        JsonDatabase {
+            version: VERSION,
+            feeds,
+            legacy: db.feeds,
+        }
+    }
+}
+
+/// Convert the content of db.json to the current layout
+fn migrate(db: Value) -> Result<JsonDatabase> {
+    match db.get("version").map(Value::as_u64) {
+        None => Ok(serde_json::from_value::<Unversioned>(db)?.into()),
+        Some(Some(VERSION)) => Ok(serde_json::from_value(db)?),
+        Some(version) => Err(anyhow!(
+            "{DB_PATH} has version {}, but only up to version {VERSION} is supported; \
+            update plato-feed instead of overwriting it",
+            version.map_or_else(|| "?".to_owned(), |v| v.to_string())
+        )),
+    }
+}
+
+/// The state of the Db at some point, to be written to db.json
+struct Snapshot {
+    /// Number of the snapshot, so an older snapshot never replaces a newer one
+    generation: u64,
+    db: JsonDatabase,
+}
+
+impl Snapshot {
+    /// Atomically replace db.json with the snapshot, unless a newer one was already written.
+    /// `written` holds the generation of the last snapshot written.
+    fn write(self, path: &Path, written: &Mutex<u64>) -> Result<()> {
+        let mut written = written.lock().unwrap_or_else(|e| e.into_inner());
+        if self.generation <= *written {
+            return Ok(());
+        }
+
+        write_atomic(path, |writer| {
+            self.db.serialize(&mut Serializer::pretty(writer))?;
+            Ok(())
+        })?;
+        *written = self.generation;
+        Ok(())
+    }
 }
 
 struct Inner {
     prev: JsonDatabase,
     new: JsonDatabase,
+    last_save: Instant,
+    /// Generation of the last [Snapshot] taken
+    generation: u64,
+    /// Entries being saved, keyed by the path of their feed's server and their id
+    saving: HashSet<(String, String)>,
 }
 
-pub struct Db(Mutex<Inner>);
+impl Inner {
+    /// Take a snapshot of the current state of the Db
+    fn snapshot(&mut self) -> Snapshot {
+        // entries of the previous run which weren't seen yet are kept, until they are
+        let mut db = self.new.clone();
+        for (path, feed) in &self.prev.feeds {
+            match db.feeds.get_mut(path) {
+                Some(new) => {
+                    for (id, entry) in &feed.entries {
+                        // removed entries are forgotten once they are no longer in the feed;
+                        // those still in it were kept when it was listed
+                        if new.listed && entry.removed.is_some() {
+                            continue;
+                        }
 
-impl Db {
-    pub fn new() -> Result<Self> {
-        let path = PathBuf::from(DB_PATH);
-        let inner = if !path.exists() {
-            Inner {
-                prev: JsonDatabase::default(),
-                new: JsonDatabase::default(),
+                        new.entries
+                            .entry(id.clone())
+                            .or_insert_with(|| entry.clone());
+                    }
+                }
+                None => {
+                    db.feeds.insert(path.clone(), feed.clone());
+                }
             }
-        } else {
-            let f = File::open(path)?;
-            let reader = BufReader::new(f);
-            Inner {
-                prev: serde_json::from_reader(reader)?,
-                new: JsonDatabase::default(),
+        }
+        db.legacy = self.prev.legacy.clone();
+        db.version = VERSION;
+
+        self.last_save = Instant::now();
+        self.generation += 1;
+        Snapshot {
+            generation: self.generation,
+            db,
+        }
+    }
+
+    /// Get the feed of the server at `path`, keeping what was known of it
+    fn feed(&mut self, path: &str) -> &mut Feed {
+        let prev = &self.prev.feeds;
+        self.new
+            .feeds
+            .entry(path.to_owned())
+            .or_insert_with(|| prev.get(path).map(Feed::metadata).unwrap_or_default())
+    }
+
+    /// Get the entry `id` of `feed`, claiming the legacy entry with that id if there is one
+    fn get(&mut self, feed: &str, id: &str) -> Option<Entry> {
+        self.new
+            .feeds
+            .get(feed)
+            .and_then(|feed| feed.entries.get(id))
+            .or_else(|| {
+                self.prev
+                    .feeds
+                    .get(feed)
+                    .and_then(|feed| feed.entries.get(id))
+            })
+            .cloned()
+            .or_else(|| self.prev.legacy.remove(id))
+    }
+
+    /// Get the entries of the feed of the server at `path` whose document is saved
+    fn saved(&self, path: &str) -> Vec<(String, Entry)> {
+        let new = self.new.feeds.get(path).map(|feed| &feed.entries);
+        let prev = self.prev.feeds.get(path).map(|feed| &feed.entries);
+        new.into_iter()
+            .flatten()
+            .chain(
+                prev.into_iter()
+                    .flatten()
+                    .filter(|(id, _)| new.is_none_or(|new| !new.contains_key(*id))),
+            )
+            .filter(|(_, entry)| entry.path.is_some() && entry.removed.is_none())
+            .map(|(id, entry)| (id.clone(), entry.clone()))
+            .collect()
+    }
+
+    /// Take a snapshot of the Db to save if it hasn't been saved for a while
+    fn checkpoint(&mut self) -> Option<Snapshot> {
+        (self.last_save.elapsed() >= CHECKPOINT_INTERVAL).then(|| self.snapshot())
+    }
+}
+
+fn read(path: &Path) -> Result<Value> {
+    let f = File::open(path)?;
+    let reader = BufReader::new(f);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Read the db.json at `path`, falling back to its backup if it is corrupt.
+/// A successfully read db.json becomes the new backup.
+fn load(path: &Path) -> Result<JsonDatabase> {
+    if !path.exists() {
+        return Ok(JsonDatabase::default());
+    }
+
+    let backup = path.with_extension(BACKUP_EXTENSION);
+    let db = match read(path) {
+        Ok(db) => {
+            let res = write_atomic(&backup, |writer| {
+                io::copy(&mut File::open(path)?, writer)?;
+                Ok(())
+            });
+            if let Err(err) = res {
+                eprintln!("feed: {:?}", err.context("backing up the database"));
             }
+
+            db
+        }
+        Err(err) => {
+            eprintln!("feed: {:?}", err.context("reading the database"));
+            let db = read(&backup).with_context(|| {
+                format!(
+                    "{} is corrupt, and couldn't be restored from {}",
+                    path.display(),
+                    backup.display()
+                )
+            })?;
+            notify(&format!(
+                "{} is corrupt, restored it from {}",
+                path.display(),
+                backup.display()
+            ));
+            db
+        }
+    };
+
+    migrate(db)
+}
+
+/// An entry being saved, which stops being one when this is dropped,
+/// whether it was saved or not
+struct Saving<'a> {
+    db: &'a Db,
+    key: (String, String),
+}
+
+impl Drop for Saving<'_> {
+    fn drop(&mut self) {
+        self.db.lock().saving.remove(&self.key);
+    }
+}
+
+pub struct Db {
+    /// Path of db.json
+    path: PathBuf,
+    inner: Mutex<Inner>,
+    /// Generation of the last [Snapshot] written, locked while one is written
+    written: Arc<Mutex<u64>>,
+}
+
+impl Db {
+    pub fn new() -> Result<Self> {
+        Db::open(Path::new(DB_PATH))
+    }
+
+    /// Open the Db saved in the db.json at `path`
+    fn open(path: &Path) -> Result<Self> {
+        let inner = Inner {
+            prev: load(path)?,
+            new: JsonDatabase::default(),
+            last_save: Instant::now(),
+            generation: 0,
+            saving: HashSet::new(),
         };
 
-        Ok(Db(Mutex::new(inner)))
+        Ok(Db {
+            path: path.to_path_buf(),
+            inner: Mutex::new(inner),
+            written: Arc::new(Mutex::new(0)),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    pub async fn update<T: Future<Output = Result<PathBuf, E>>, E>(
+    /// Write the `snapshot` in the background, so neither the Db nor the runtime wait for it
+    fn write_checkpoint(&self, snapshot: Snapshot) {
+        let path = self.path.clone();
+        let written = Arc::clone(&self.written);
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = snapshot.write(&path, &written) {
+                eprintln!(
+                    "feed: {:?}",
+                    err.context("saving checkpoint of the database")
+                );
+            }
+        });
+    }
+
+    /// Save the entry `id` of `feed` with `save_file` if it is new, or has been `updated` since
+    /// it was last saved and the `policy` allows updating it. `save_file` is given the previous
+    /// document of the entry, if any, to replace it, and the number of the entry in the series of
+    /// its feed. It returns `None` if the content of the entry is unchanged.
+    /// The Db is only locked to look up and record the entry, so entries can be saved concurrently.
+    /// An entry already being saved, such as one listed twice in its feed, is skipped.
+    pub async fn update<F, T, E>(
         &self,
+        feed: &str,
         id: String,
         updated: Option<DateTime<Utc>>,
-        save_file: T,
-    ) -> Result<(), E> {
-        let mut inner = self.0.lock().await;
-        match inner.prev.feeds.remove(&id) {
-            // no need to update; just keep the previous entry
-            Some(entry) if updated.is_none_or(|u| entry.last_update >= u) => {
-                inner.new.feeds.insert(id, entry);
-                Ok(())
+        policy: UpdatePolicy,
+        save_file: F,
+    ) -> Result<(), E>
+    where
+        F: FnOnce(Option<Saved>, u64) -> T,
+        T: Future<Output = Result<Option<Document>, E>>,
+        E: Display,
+    {
+        let (entry, number, saving) = {
+            let mut inner = self.lock();
+            let key = (feed.to_owned(), id.clone());
+            if inner.saving.contains(&key) {
+                return Ok(());
             }
-            // upsert!
-            entry => match save_file.await {
-                // update succeeded! get new entry!
-                Ok(path) => {
-                    inner.new.feeds.insert(
-                        id,
-                        Entry {
-                            path,
-                            last_update: updated.unwrap_or_else(Utc::now),
-                        },
-                    );
-                    Ok(())
+
+            let entry = match inner.get(feed, &id) {
+                // no need to update; just keep the previous entry
+                Some(entry)
+                    if entry.removed.is_some()
+                        || (entry.path.is_some()
+                            && (policy == UpdatePolicy::Never
+                                || updated.is_none_or(|u| entry.last_update >= u))) =>
+                {
+                    inner.feed(feed).entries.insert(id, entry);
+                    return Ok(());
                 }
-                Err(err) => {
-                    if let Some(entry) = entry {
-                        // failed to update; just keep the previous entry if it exists
-                        inner.new.feeds.insert(id, entry);
-                    }
+                entry => entry,
+            };
 
-                    Err(err)
+            // entries keep their number when they are updated
+            let number = match entry.as_ref().and_then(|entry| entry.number) {
+                Some(number) => number,
+                None => {
+                    let feed = inner.feed(feed);
+                    feed.number += 1;
+                    feed.number
                 }
-            },
+            };
+            inner.saving.insert(key.clone());
+            (entry, number, Saving { db: self, key })
+        };
+
+        // upsert!
+        let previous = entry.as_ref().and_then(|entry| {
+            Some(Saved {
+                path: entry.path.clone()?,
+                content_hash: entry.content_hash.clone(),
+            })
+        });
+        let res = save_file(previous, number).await;
+        drop(saving);
+        let mut inner = self.lock();
+        match res {
+            // update succeeded! get new entry!
+            Ok(Some(document)) => {
+                inner.feed(feed).entries.insert(
+                    id,
+                    Entry {
+                        path: Some(document.path),
+                        last_update: updated.unwrap_or_else(Utc::now),
+                        title: Some(document.title),
+                        link: document.link,
+                        feed: Some(document.feed),
+                        content_hash: Some(document.content_hash),
+                        size: Some(document.size),
+                        number: Some(number),
+                        fetched: Some(Utc::now()),
+                        failures: 0,
+                        last_error: None,
+                        removed: None,
+                    },
+                );
+                let snapshot = inner.checkpoint();
+                drop(inner);
+                if let Some(snapshot) = snapshot {
+                    self.write_checkpoint(snapshot);
+                }
+                Ok(())
+            }
+            // the content is unchanged; just remember the entry was seen updated
+            Ok(None) => {
+                let mut entry = entry.unwrap_or_default();
+                entry.last_update = updated.unwrap_or_else(Utc::now);
+                entry.number = Some(number);
+                entry.failures = 0;
+                entry.last_error = None;
+                inner.feed(feed).entries.insert(id, entry);
+                Ok(())
+            }
+            Err(err) => {
+                // failed to update; keep the previous entry if it exists, and record the failure
+                let mut entry = entry.unwrap_or_default();
+                entry.failures += 1;
+                entry.last_error = Some(format!("{err:#}"));
+                inner.feed(feed).entries.insert(id, entry);
+                Err(err)
+            }
+        }
+    }
+
+    /// Get the validators saved for the feed of the server at `path`, if it is still at `url`
+    pub fn validators(&self, path: &str, url: &str) -> Validators {
+        let inner = self.lock();
+        inner
+            .new
+            .feeds
+            .get(path)
+            .or_else(|| inner.prev.feeds.get(path))
+            .filter(|feed| feed.url == url)
+            .map(|feed| feed.validators.clone())
+            .unwrap_or_default()
+    }
+
+    /// Record that the feed of the server at `path` was downloaded from `url`
+    pub fn fetched(&self, path: &str, url: &str) {
+        let mut inner = self.lock();
+        let feed = inner.feed(path);
+        if feed.url != url {
+            feed.url = url.to_owned();
+            feed.validators = Validators::default();
+            feed.redirect = None;
+        }
+        feed.last_fetch = Some(Utc::now());
+    }
+
+    /// Record the URL the feed of the server at `path` was redirected to, if any
+    pub fn set_redirect(&self, path: &str, redirect: Option<String>) {
+        self.lock().feed(path).redirect = redirect;
+    }
+
+    /// Record that the entries of the feed of the server at `path` were listed, and that `ids`
+    /// are those still in it, so the entries which are no longer in it can be forgotten.
+    /// The removed entries still in it are kept right away, rather than once their entry is
+    /// updated, so they aren't forgotten if the Db is saved before, or instead of, that.
+    pub fn listed<'a>(&self, path: &str, ids: impl IntoIterator<Item = &'a str>) {
+        let mut inner = self.lock();
+        let removed = inner
+            .prev
+            .feeds
+            .get(path)
+            .map(|feed| {
+                ids.into_iter()
+                    .filter_map(|id| {
+                        let entry = feed.entries.get(id)?;
+                        entry.removed.map(|_| (id.to_owned(), entry.clone()))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let feed = inner.feed(path);
+        feed.listed = true;
+        for (id, entry) in removed {
+            feed.entries.entry(id).or_insert(entry);
+        }
+    }
+
+    /// Mark the entries of the feed of the server at `path` which are expired according to
+    /// `retention` as removed. Returns the paths of their documents, to be removed.
+    pub fn expire(&self, path: &str, retention: &RetentionSettings) -> Vec<PathBuf> {
+        if retention.is_empty() {
+            return Vec::new();
+        }
+
+        let mut inner = self.lock();
+        let mut saved = inner.saved(path);
+        // newest first
+        saved.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_update));
+
+        let now = Utc::now();
+        // a cutoff too far in the past to be represented keeps every entry
+        let cutoff = retention
+            .keep_days
+            .and_then(|days| now.checked_sub_signed(chrono::Duration::days(days.into())));
+        let max_size = retention
+            .keep_size
+            .map(|size| size.saturating_mul(1024 * 1024));
+        let mut size = 0;
+        let mut expired = Vec::new();
+        for (i, (id, mut entry)) in saved.into_iter().enumerate() {
+            size += entry.size.unwrap_or_default();
+            let expire = retention.keep_entries.is_some_and(|n| i >= n)
+                || cutoff.is_some_and(|cutoff| entry.last_update < cutoff)
+                || max_size.is_some_and(|max| size > max);
+            if !expire {
+                continue;
+            }
+
+            if let Some(document) = entry.path.take() {
+                expired.push(document);
+            }
+            entry.size = None;
+            entry.removed = Some(now);
+            inner.feed(path).entries.insert(id, entry);
         }
+
+        expired
+    }
+
+    /// Mark the entry of the feed of the server at `path` whose document is at `document` as
+    /// removed. Returns whether there is such an entry.
+    pub fn remove(&self, path: &str, document: &Path) -> bool {
+        let mut inner = self.lock();
+        let Some((id, mut entry)) = inner
+            .saved(path)
+            .into_iter()
+            .find(|(_, entry)| entry.path.as_deref() == Some(document))
+        else {
+            return false;
+        };
+
+        entry.path = None;
+        entry.size = None;
+        entry.removed = Some(Utc::now());
+        inner.feed(path).entries.insert(id, entry);
+        true
+    }
+
+    /// Get the entries of the feed of the server at `path` whose document is saved
+    pub fn records(&self, path: &str) -> Vec<Record> {
+        self.lock()
+            .saved(path)
+            .into_iter()
+            .filter_map(|(id, entry)| {
+                Some(Record {
+                    id,
+                    path: entry.path?,
+                    title: entry.title,
+                    feed: entry.feed,
+                    number: entry.number,
+                    last_update: entry.last_update,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the paths of the documents of every entry
+    pub fn documents(&self) -> HashSet<PathBuf> {
+        let inner = self.lock();
+        let entries = |db: &JsonDatabase| {
+            db.feeds
+                .values()
+                .flat_map(|feed| feed.entries.values())
+                .chain(db.legacy.values())
+                .filter_map(|entry| entry.path.clone())
+                .collect::<Vec<_>>()
+        };
+        entries(&inner.new)
+            .into_iter()
+            .chain(entries(&inner.prev))
+            .collect()
+    }
+
+    /// Forget the entry `id` of the feed of the server at `path`, so it is downloaded again
+    pub fn forget(&self, path: &str, id: &str) {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        for db in [&mut inner.new, &mut inner.prev] {
+            if let Some(feed) = db.feeds.get_mut(path) {
+                feed.entries.remove(id);
+            }
+        }
+    }
+
+    /// Add an entry to the feed of the server at `path` for a `document` which wasn't saved by it.
+    /// The entry can't be matched to an entry of the feed, but is removed like one.
+    pub fn adopt(&self, path: &str, document: PathBuf, last_update: DateTime<Utc>, size: u64) {
+        let id = format!("file:{}", document.display());
+        let title = document
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned());
+        let entry = Entry {
+            path: Some(document),
+            last_update,
+            title,
+            size: Some(size),
+            fetched: Some(Utc::now()),
+            ..Entry::default()
+        };
+        self.lock().feed(path).entries.insert(id, entry);
+    }
+
+    /// Save the `validators` for the feed of the server at `path`,
+    /// to be used the next time it is downloaded
+    pub fn set_validators(&self, path: &str, validators: Validators) {
+        self.lock().feed(path).validators = validators;
     }
 }
 
 impl Drop for Db {
     fn drop(&mut self) {
-        let writer = match File::create(DB_PATH) {
-            Ok(f) => BufWriter::new(f),
-            Err(err) => {
-                eprintln!("feed: {:?}", err);
-                return;
-            }
+        let inner = self.inner.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Err(err) = inner.snapshot().write(&self.path, &self.written) {
+            eprintln!("feed: {:?}", err.context("saving the database"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use chrono::TimeZone;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const FEED: &str = "Category/Server";
+
+    /// Open an empty Db in a temporary directory, which is kept until the Db is dropped
+    fn open() -> (Db, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        (Db::open(&dir.path().join(DB_PATH)).unwrap(), dir)
+    }
+
+    fn date(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap()
+    }
+
+    fn document(id: &str) -> Document {
+        Document {
+            path: PathBuf::from(format!("{id}.epub")),
+            title: id.to_owned(),
+            link: None,
+            feed: "Feed".to_owned(),
+            content_hash: String::new(),
+            size: 1,
+        }
+    }
+
+    /// Update the entry `id`, updated on `day`, as allowed by `policy`, with `save_file` giving
+    /// its new document, if its content changed.
+    /// Returns how many times `save_file` was called, with the previous document of the entry.
+    async fn update(
+        db: &Db,
+        id: &str,
+        day: u32,
+        policy: UpdatePolicy,
+        save_file: impl Fn(Option<Saved>) -> Option<Document>,
+    ) -> usize {
+        let saves = AtomicUsize::new(0);
+        db.update(
+            FEED,
+            id.to_owned(),
+            Some(date(day)),
+            policy,
+            |previous, _| {
+                saves.fetch_add(1, Ordering::Relaxed);
+                let document = save_file(previous);
+                async { Ok::<_, anyhow::Error>(document) }
+            },
+        )
+        .await
+        .unwrap();
+        saves.into_inner()
+    }
+
+    /// Save the entry `id`, updated on `day`, returning how many times its document was saved
+    async fn save(db: &Db, id: &str, day: u32) -> usize {
+        update(db, id, day, UpdatePolicy::Updated, |_| Some(document(id))).await
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        let db = migrate(json!({
+            "feeds": { "legacy": { "path": "legacy.epub" } },
+            "entries": { FEED: { "entry": { "path": "entry.epub" } } },
+        }))
+        .unwrap();
+        assert_eq!(db.version, VERSION);
+        assert!(db.legacy.contains_key("legacy"));
+        assert!(db.feeds[FEED].entries.contains_key("entry"));
+    }
+
+    #[test]
+    fn migrate_refuses_newer_versions() {
+        assert!(migrate(json!({ "version": VERSION + 1 })).is_err());
+        assert!(migrate(json!({ "version": "2" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn update_saves_new_and_updated_entries_only() {
+        let (db, _dir) = open();
+        assert_eq!(save(&db, "entry", 1).await, 1);
+        assert_eq!(save(&db, "entry", 1).await, 0);
+        assert_eq!(save(&db, "entry", 2).await, 1);
+    }
+
+    #[tokio::test]
+    async fn update_policy_never_keeps_the_first_document() {
+        let (db, _dir) = open();
+        save(&db, "entry", 1).await;
+        let saves = update(&db, "entry", 2, UpdatePolicy::Never, |_| {
+            Some(document("other"))
+        })
+        .await;
+        assert_eq!(saves, 0);
+
+        let records = db.records(FEED);
+        assert_eq!(records[0].path, PathBuf::from("entry.epub"));
+        assert_eq!(records[0].last_update, date(1));
+    }
+
+    #[tokio::test]
+    async fn update_policy_content_keeps_unchanged_documents() {
+        let (db, _dir) = open();
+        save(&db, "entry", 1).await;
+        let unchanged = |previous: Option<Saved>| {
+            let previous = previous.unwrap();
+            assert_eq!(previous.path, PathBuf::from("entry.epub"));
+            assert_eq!(previous.content_hash.as_deref(), Some(""));
+            None
         };
+        assert_eq!(
+            update(&db, "entry", 2, UpdatePolicy::Content, unchanged).await,
+            1
+        );
 
-        let inner = self.0.get_mut();
-        for (feed_name, feed) in inner.prev.feeds.drain() {
-            if !inner.new.feeds.contains_key(&feed_name) {
-                inner.new.feeds.insert(feed_name, feed);
-            }
+        let records = db.records(FEED);
+        assert_eq!(records[0].path, PathBuf::from("entry.epub"));
+        assert_eq!(records[0].title.as_deref(), Some("entry"));
+        assert_eq!(records[0].number, Some(1));
+        // but the update was seen, so the entry isn't checked again until it is updated again
+        assert_eq!(records[0].last_update, date(2));
+        assert_eq!(
+            update(&db, "entry", 2, UpdatePolicy::Content, unchanged).await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_entries_are_saved_once() {
+        let (db, _dir) = open();
+        let saves = AtomicUsize::new(0);
+        let update = || {
+            db.update(
+                FEED,
+                "entry".to_owned(),
+                Some(date(1)),
+                UpdatePolicy::Updated,
+                |_, _| async {
+                    saves.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                    Ok::<_, anyhow::Error>(Some(document("entry")))
+                },
+            )
+        };
+        let (first, second) = tokio::join!(update(), update());
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(saves.into_inner(), 1);
+        assert_eq!(db.records(FEED).len(), 1);
+
+        // the entry can be saved again once it was
+        assert_eq!(save(&db, "entry", 2).await, 1);
+    }
+
+    #[tokio::test]
+    async fn expire_keeps_the_newest_entries() {
+        let (db, _dir) = open();
+        for (id, day) in [("old", 1), ("new", 3), ("middle", 2)] {
+            save(&db, id, day).await;
         }
 
-        let mut serializer = Serializer::pretty(writer);
-        if let Err(err) = inner.new.serialize(&mut serializer) {
-            eprintln!("feed: {:?}", err);
+        let retention = RetentionSettings {
+            keep_entries: Some(1),
+            ..RetentionSettings::default()
+        };
+        let mut expired = db.expire(FEED, &retention);
+        expired.sort();
+        assert_eq!(
+            expired,
+            [PathBuf::from("middle.epub"), PathBuf::from("old.epub")]
+        );
+        let records = db.records(FEED);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "new");
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_not_downloaded_again() {
+        let (db, dir) = open();
+        save(&db, "entry", 1).await;
+        let retention = RetentionSettings {
+            keep_days: Some(1),
+            ..RetentionSettings::default()
+        };
+        assert_eq!(db.expire(FEED, &retention).len(), 1);
+        assert_eq!(save(&db, "entry", 2).await, 0);
+
+        // even once the Db is saved and loaded again
+        drop(db);
+        let db = Db::open(&dir.path().join(DB_PATH)).unwrap();
+        assert_eq!(save(&db, "entry", 3).await, 0);
+        assert!(db.records(FEED).is_empty());
+    }
+
+    #[tokio::test]
+    async fn removed_entries_are_forgotten_once_no_longer_listed() {
+        let (db, dir) = open();
+        for id in ["listed", "unlisted"] {
+            save(&db, id, 1).await;
+            assert!(db.remove(FEED, Path::new(&format!("{id}.epub"))));
         }
+        drop(db);
+
+        // saved before the entries still in the feed were updated, as when the hook is stopped
+        let db = Db::open(&dir.path().join(DB_PATH)).unwrap();
+        db.listed(FEED, ["listed"]);
+        drop(db);
+
+        let db = Db::open(&dir.path().join(DB_PATH)).unwrap();
+        assert_eq!(save(&db, "listed", 2).await, 0);
+        assert_eq!(save(&db, "unlisted", 2).await, 1);
+    }
+
+    #[tokio::test]
+    async fn removed_entries_are_not_downloaded_again() {
+        let (db, _dir) = open();
+        save(&db, "entry", 1).await;
+        assert!(!db.remove(FEED, Path::new("other.epub")));
+        assert!(db.remove(FEED, Path::new("entry.epub")));
+        assert!(db.records(FEED).is_empty());
+        assert_eq!(save(&db, "entry", 2).await, 0);
     }
 }
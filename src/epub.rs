@@ -0,0 +1,122 @@
+//! Building EPUBs on disk. epub-builder's [ZipLibrary](epub_builder::ZipLibrary) keeps the whole
+//! EPUB in memory until it is generated, images included, which is more than an e-reader can
+//! spare for articles with many images.
+//!
+//! epub-builder only writes custom metadata as `<meta>` elements, so the [SOURCE] of the EPUB is
+//! turned into a Dublin Core `<dc:source>` element as the package document is written.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use epub_builder::Zip;
+use lazy_static::lazy_static;
+use regex::Regex;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// Name of the metadata holding the URL of the article an EPUB was made from
+pub const SOURCE: &str = "dc:source";
+
+lazy_static! {
+    static ref SOURCE_META_REGEX: Regex = Regex::new(&format!(
+        r#"<meta\s+name\s*=\s*"{SOURCE}"\s+content\s*=\s*"([^"]*)"\s*/?>"#
+    ))
+    .unwrap();
+}
+
+/// A [Zip] written to an anonymous temporary file as the files of the EPUB are added to it
+pub struct FileZip {
+    writer: ZipWriter<File>,
+}
+
+impl FileZip {
+    /// Start an EPUB in an anonymous temporary file in `tmp_dir`
+    pub fn new(tmp_dir: &Path) -> Result<FileZip> {
+        let mut writer = ZipWriter::new(tempfile::tempfile_in(tmp_dir)?);
+        // the mimetype must be the first file of an EPUB, and not be compressed
+        writer.start_file(
+            "mimetype",
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        writer.write_all(b"application/epub+zip")?;
+        Ok(FileZip { writer })
+    }
+}
+
+impl Zip for FileZip {
+    fn write_file<P: AsRef<Path>, R: Read>(&mut self, path: P, mut content: R) -> eyre::Result<()> {
+        // paths in a zip are always separated by `/`
+        let path = path.as_ref().to_string_lossy().replace('\\', "/");
+        let package = path.ends_with(".opf");
+        self.writer.start_file(path, FileOptions::default())?;
+        if package {
+            let mut opf = String::new();
+            content.read_to_string(&mut opf)?;
+            let opf = SOURCE_META_REGEX.replace_all(&opf, "<dc:source>$1</dc:source>");
+            self.writer.write_all(opf.as_bytes())?;
+        } else {
+            io::copy(&mut content, &mut self.writer)?;
+        }
+        Ok(())
+    }
+
+    fn generate<W: Write>(&mut self, mut to: W) -> eyre::Result<()> {
+        let mut file = self.writer.finish()?;
+        file.rewind()?;
+        io::copy(&mut file, &mut to)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_the_files_written_to_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut zip = FileZip::new(dir.path()).unwrap();
+        zip.write_file("OEBPS/article.html", &b"<html></html>"[..])
+            .unwrap();
+        let mut epub = Vec::new();
+        zip.generate(&mut epub).unwrap();
+
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(epub)).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        let mut article = String::new();
+        archive
+            .by_name("OEBPS/article.html")
+            .unwrap()
+            .read_to_string(&mut article)
+            .unwrap();
+        assert_eq!(article, "<html></html>");
+        // nothing is left behind in the directory
+        assert_eq!(dir.path().read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn writes_the_source_as_dublin_core() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut zip = FileZip::new(dir.path()).unwrap();
+        let opf =
+            r#"<metadata><meta name="dc:source" content="https://a.com/?b=1&amp;c=2"/></metadata>"#;
+        zip.write_file("OEBPS/content.opf", opf.as_bytes()).unwrap();
+        let mut epub = Vec::new();
+        zip.generate(&mut epub).unwrap();
+
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(epub)).unwrap();
+        let mut opf = String::new();
+        archive
+            .by_name("OEBPS/content.opf")
+            .unwrap()
+            .read_to_string(&mut opf)
+            .unwrap();
+        assert_eq!(
+            opf,
+            "<metadata><dc:source>https://a.com/?b=1&amp;c=2</dc:source></metadata>"
+        );
+    }
+}
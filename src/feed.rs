@@ -1,98 +1,348 @@
-use std::{fs, io::Cursor, path::PathBuf, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::{anyhow, Context, Result};
-use bytes::Bytes;
 use chrono::{Local, Utc};
-use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
+use epub_builder::{EpubBuilder, EpubContent, MetadataOpf, PageDirection};
 use feed_rs::{
     model::{Content, Link},
     parser,
 };
+use flate2::read::MultiGzDecoder;
 use maud::{html, DOCTYPE};
 use mime_guess::MimeGuess;
-use serde_json::json;
 use sha2::{Digest, Sha256};
-use tokio::task::JoinHandle;
+use tokio::{sync::Semaphore, task::JoinHandle};
 use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    charset::{decode_feed, decode_html},
+    client::{Client, Resource, Validators},
+    db::{Db, Document, Saved},
+    epub::{FileZip, SOURCE},
+    html::{clean_html, Article},
+    lang::{self, language_direction, set_lang_dir, text_direction, Direction},
+    persist::PartialFile,
+    plato::{notify, Event, FileInfo, Info, Messages},
+    settings::{EntryId, Instance, RetentionSettings, Server, UpdatePolicy},
+};
 
-use crate::{client::Client, db::Db, html::clean_html, plato::notify, settings::Instance};
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
 
 pub fn program_name() -> String {
     format!("plato-feed/{}", env!("CARGO_PKG_VERSION"))
 }
 
-fn find_link(links: &Vec<Link>) -> Option<&Link> {
+/// Decompress feeds published as gzip files (e.g. `feed.xml.gz`), up to `max_size` bytes.
+/// Compression negotiated with the `Content-Encoding` header is already undone by the client.
+fn decompress_feed(body: &[u8], max_size: usize) -> Result<Cow<'_, [u8]>> {
+    if !body.starts_with(GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(body));
+    }
+
+    let mut feed = Vec::new();
+    MultiGzDecoder::new(body)
+        .take((max_size as u64).saturating_add(1))
+        .read_to_end(&mut feed)
+        .context("decompressing gzip feed")?;
+    if feed.len() > max_size {
+        return Err(anyhow!(
+            "decompressed feed is larger than the maximum of {} KiB",
+            max_size / 1024
+        ));
+    }
+
+    Ok(Cow::Owned(feed))
+}
+
+fn find_link(links: &[Link]) -> Option<&Link> {
     links
         .iter()
-        .find(|l| {
-            l.media_type
-                .as_ref()
-                .map_or(false, |mt| mt.contains("html"))
-        })
+        .find(|l| l.media_type.as_ref().is_some_and(|mt| mt.contains("html")))
         .or_else(|| links.iter().find(|l| l.media_type.is_none()))
         .or_else(|| links.first())
 }
 
+/// The entries being loaded for a feed
+pub struct FeedTasks {
+    /// One task per entry of the feed
+    pub tasks: Vec<JoinHandle<Result<()>>>,
+    /// The validators of the downloaded feed, which should only be saved to the [Db] once every
+    /// task has succeeded, so failed entries are retried the next time.
+    /// `None` if the feed has not been modified since the last time it was downloaded.
+    pub validators: Option<Validators>,
+}
+
+/// Parse a feed, leaving the ids of the entries without one empty rather than making them up
+/// from their link and title, or at random, so [entry_id] can fall back to a stable identity
+fn parse_feed(feed: &[u8]) -> Result<feed_rs::model::Feed> {
+    let parser = parser::Builder::new()
+        .id_generator(|_, _, _| String::new())
+        .build();
+    Ok(parser.parse(feed)?)
+}
+
+/// Identify `entry` as configured by `entry_id`, falling back to the next most stable identity
+/// when the entry doesn't have the configured one.
+/// Returns `None` if the entry has nothing to be identified by.
+fn entry_id(entry: &feed_rs::model::Entry, entry_id: EntryId) -> Option<String> {
+    let id = entry.id.trim();
+    if entry_id == EntryId::Id && !id.is_empty() {
+        return Some(id.to_owned());
+    }
+
+    if entry_id != EntryId::Title {
+        if let Some(link) = find_link(&entry.links) {
+            return Some(link.href.clone());
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    let date = entry.published.or(entry.updated);
+    if entry.title.is_some() || date.is_some() {
+        if let Some(title) = &entry.title {
+            hasher.update(&title.content);
+        }
+        if let Some(date) = date {
+            hasher.update(date.to_rfc3339());
+        }
+    } else {
+        // entries without a title or date could only be told apart by their content
+        let summary = entry.summary.as_ref().map(|summary| &summary.content);
+        let content = entry
+            .content
+            .as_ref()
+            .and_then(|content| content.body.as_ref());
+        if summary.is_none() && content.is_none() {
+            return None;
+        }
+
+        for text in [summary, content].into_iter().flatten() {
+            hasher.update(text);
+            hasher.update([0]);
+        }
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+/// Remove the documents in `save_dir` of the entries of the feed of the server at `path` which
+/// expired according to `retention`, and tell Plato to remove them from its library.
+/// Returns the number of documents removed.
+pub fn remove_expired(
+    db: &Db,
+    path: &str,
+    save_dir: &Path,
+    retention: &RetentionSettings,
+    library_path: &Path,
+) -> usize {
+    let mut removed = 0;
+    for document in db.expire(path, retention) {
+        // documents moved elsewhere are the user's to keep
+        if !document.starts_with(save_dir) || !document.exists() {
+            continue;
+        }
+
+        if let Err(err) = fs::remove_file(&document) {
+            eprintln!(
+                "feed: {:?}",
+                anyhow!(err).context(format!("removing {}", document.display()))
+            );
+            continue;
+        }
+
+        removed += 1;
+        if let Ok(path) = document.strip_prefix(library_path) {
+            Event::RemoveDocument { path }.send();
+        }
+    }
+
+    removed
+}
+
+/// Remove the documents in `save_dir` of the entries of the feed of the server at `path` which
+/// have been marked as finished in Plato, and not opened for the last `days`.
+/// Returns the number of documents removed.
+pub async fn remove_finished(
+    db: &Db,
+    messages: &mut Messages,
+    path: &str,
+    save_dir: &Path,
+    days: u32,
+    library_path: &Path,
+) -> Result<usize> {
+    // a cutoff too far in the past to be represented keeps every document
+    let Some(cutoff) = Local::now()
+        .naive_local()
+        .checked_sub_signed(chrono::Duration::days(days.into()))
+    else {
+        return Ok(0);
+    };
+    let mut removed = 0;
+    for info in messages.search(save_dir).await? {
+        let finished = info.reader.as_ref().is_some_and(|reader| {
+            reader.finished && reader.opened().is_some_and(|opened| opened <= cutoff)
+        });
+        let document = library_path.join(&info.file.path);
+        if !finished || !document.starts_with(save_dir) || !db.remove(path, &document) {
+            continue;
+        }
+
+        if let Err(err) = fs::remove_file(&document) {
+            eprintln!(
+                "feed: {:?}",
+                anyhow!(err).context(format!("removing {}", document.display()))
+            );
+            continue;
+        }
+
+        removed += 1;
+        Event::RemoveDocument {
+            path: &info.file.path,
+        }
+        .send();
+    }
+
+    Ok(removed)
+}
+
+/// What the entries of a feed share while they are loaded
+pub struct FeedContext {
+    pub client: Client,
+    /// Host of the feed, against which the relative links of its entries are resolved
+    pub base: Option<String>,
+    pub library_path: Arc<PathBuf>,
+    /// Title of the feed, or name of its server if it has none
+    pub publisher: String,
+    /// Directory the documents of the entries are saved to
+    pub save_path: PathBuf,
+    pub instance: Instance,
+    /// Links of the feed itself
+    pub links: Vec<Link>,
+    /// The category of the server in the settings, which is the path of its parent directory
+    pub category: Option<String>,
+    /// Number of images of an entry to download concurrently
+    pub concurrent_images: usize,
+}
+
+/// Download the feed of `server`, whose entries are saved in the [Db] under its path,
+/// and start saving its entries
 pub async fn load_feed(
     db: Arc<Db>,
-    server: Arc<String>,
-    instance: Arc<Instance>,
+    server: Server,
     client: Client,
     library_path: Arc<PathBuf>,
-    save_dir: Arc<PathBuf>,
-) -> Result<Vec<JoinHandle<Result<()>>>> {
+    entries: Arc<Semaphore>,
+    concurrent_images: usize,
+) -> Result<FeedTasks> {
+    let Server {
+        server,
+        path,
+        dir,
+        instance,
+    } = server;
     notify(&format!("loading {}", &server));
-    let res = client.get(&instance.url).await?;
+    let validators = db.validators(&path, &instance.url);
+    let res = client.get_conditional(&instance.url, &validators).await?;
+    let Some(res) = res else {
+        db.fetched(&path, &instance.url);
+        return Ok(FeedTasks {
+            tasks: Vec::new(),
+            validators: None,
+        });
+    };
     let base = Url::parse(&instance.url).ok().and_then(|u| match u.host() {
         Some(url::Host::Domain(host)) => Some(host.to_owned()),
         _ => None,
     });
-    let feed = parser::parse(res.body.as_ref())?;
-    let publisher = if let Some(title) = feed.title {
-        Arc::new(title.content)
-    } else {
-        Arc::clone(&server)
-    };
-    let links = Arc::new(feed.links);
+    let redirect = Url::parse(&instance.url)
+        .ok()
+        .filter(|url| url != &res.url)
+        .map(|_| res.url.to_string());
+    let body = decompress_feed(&res.body, client.max_size(Resource::Feed))?;
+    let feed = parse_feed(decode_feed(&body, res.content_type.as_ref()).as_ref())?;
+    // only a feed which could be read counts as fetched
+    db.fetched(&path, &instance.url);
+    db.set_redirect(&path, redirect);
+    let language = feed.language;
+    let feed_entries = feed
+        .entries
+        .into_iter()
+        .filter_map(|mut entry| {
+            let Some(id) = entry_id(&entry, instance.entry_id) else {
+                eprintln!("feed: skipping an entry of {server} with nothing to identify it by");
+                return None;
+            };
+            entry.id = id;
+            if entry.language.is_none() {
+                entry.language = language.clone();
+            }
+            Some(entry)
+        })
+        .collect::<Vec<_>>();
+    db.listed(&path, feed_entries.iter().map(|entry| entry.id.as_str()));
+    let category = path.rsplit_once('/').map(|(parent, _)| parent.to_owned());
+    let context = Arc::new(FeedContext {
+        client: client.for_entries(),
+        base,
+        library_path,
+        publisher: feed
+            .title
+            .map_or_else(|| server.clone(), |title| title.content),
+        save_path: dir,
+        instance,
+        links: feed.links,
+        category,
+        concurrent_images,
+    });
 
+    let path = Arc::new(path);
+    let server = Arc::new(server);
     let mut tasks = Vec::new();
-    for entry in feed.entries {
+    for entry in feed_entries {
         let db = Arc::clone(&db);
-        let client = client.clone();
-        let base = base.clone();
-        let library_path = Arc::clone(&library_path);
-        let save_dir = Arc::clone(&save_dir);
-        let publisher = Arc::clone(&publisher);
-        let instance = Arc::clone(&instance);
+        let context = Arc::clone(&context);
+        let path = Arc::clone(&path);
         let server = Arc::clone(&server);
-        let links = Arc::clone(&links);
+        let entries = Arc::clone(&entries);
         let task = tokio::spawn(async move {
             let id = entry.id.clone();
-            db.update(
+            let shutdown = context.client.clone();
+            let policy = context.instance.update_policy;
+            let update = db.update(
+                &path,
                 id.clone(),
                 entry.updated,
-                load_entry(
-                    entry,
-                    base,
-                    client,
-                    library_path,
-                    publisher,
-                    save_dir,
-                    instance,
-                    links,
-                ),
-            )
-            .await
-            .with_context(|| format!("{} of {}", id, &Arc::clone(&server)))
+                policy,
+                |previous, number| async move {
+                    // only wait for a permit once the entry is known to need updating
+                    let _permit = entries.acquire().await?;
+                    load_entry(entry, &context, previous, number).await
+                },
+            );
+            tokio::select! {
+                _ = shutdown.cancelled() => Err(anyhow!("cancelled")),
+                res = update => res,
+            }
+            .with_context(|| format!("{} of {}", id, &server))
         });
         tasks.push(task);
     }
 
-    Ok(tasks)
+    Ok(FeedTasks {
+        tasks,
+        validators: Some(res.validators),
+    })
 }
 
 fn add_cover_img<'a>(
-    builder: &mut EpubBuilder<ZipLibrary>,
+    builder: &mut EpubBuilder<FileZip>,
     img: &'a PathBuf,
     publisher: &str,
 ) -> Result<&'a str> {
@@ -126,18 +376,24 @@ fn add_cover_img<'a>(
 
 async fn load_entry(
     entry: feed_rs::model::Entry,
-    base: Option<String>,
-    client: Client,
-    library_path: Arc<PathBuf>,
-    publisher: Arc<String>,
-    save_path: Arc<PathBuf>,
-    server_instance: Arc<Instance>,
-    links: Arc<Vec<Link>>,
-) -> Result<PathBuf> {
-    let mut builder: EpubBuilder<ZipLibrary> =
-        EpubBuilder::new(ZipLibrary::new().map_err(|e| anyhow!(e))?).map_err(|e| anyhow!(e))?;
-
-    let img = if let Some(img) = &server_instance.title_img {
+    context: &FeedContext,
+    previous: Option<Saved>,
+    number: u64,
+) -> Result<Option<Document>> {
+    let FeedContext {
+        client: _,
+        base,
+        library_path,
+        publisher,
+        save_path,
+        instance,
+        links,
+        category,
+        concurrent_images: _,
+    } = context;
+    let mut builder = EpubBuilder::new(FileZip::new(save_path)?).map_err(|e| anyhow!(e))?;
+
+    let img = if let Some(img) = &instance.title_img {
         match add_cover_img(&mut builder, img, publisher.as_str()) {
             Ok(img) => Some(img),
             Err(err) => {
@@ -156,10 +412,7 @@ async fn load_entry(
         .filter(|a| !a.is_empty())
         .collect();
     if authors.is_empty() {
-        let author = server_instance
-            .default_author
-            .as_ref()
-            .unwrap_or(&publisher);
+        let author = instance.default_author.as_ref().unwrap_or(publisher);
         if !author.is_empty() {
             authors.push(author.to_owned());
         }
@@ -168,6 +421,10 @@ async fn load_entry(
     builder.set_authors(authors);
 
     builder.set_generator(program_name());
+    // the same entry always makes the same book, even when it is downloaded again,
+    // while entries with the same id in different feeds make different books
+    let name = format!("{}#{}", instance.url, entry.id);
+    builder.set_uuid(Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()));
 
     let date = if let Some(date) = entry.published {
         date
@@ -177,6 +434,9 @@ async fn load_entry(
         Utc::now()
     };
     builder.set_publication_date(date);
+    if let Some(updated) = entry.updated {
+        builder.set_modified_date(updated);
+    }
     let year = date.format("%Y").to_string();
     let date = date.format("%Y%m%dT%H%M%S").to_string();
 
@@ -187,15 +447,26 @@ async fn load_entry(
         entry.id.clone()
     };
 
-    let mut hasher = Sha256::new();
-    hasher.update(&entry.id);
-    let filename = format!("{}-{:x}.epub", date, hasher.finalize());
-    let filename = save_path.join(filename);
-    let path = filename.strip_prefix(library_path.as_ref())?;
+    let mut categories = entry
+        .categories
+        .into_iter()
+        .map(|c| c.label.unwrap_or(c.term))
+        .filter(|c| !c.is_empty())
+        .collect::<BTreeSet<_>>();
+    for subject in &categories {
+        builder.add_subject(subject);
+    }
+    categories.extend(category.clone());
 
     let link = find_link(&entry.links);
-    let content = if Some(true) == server_instance.download_full_article {
-        download_full_article(link, &mut builder, client, Arc::clone(&server_instance)).await?
+    if let Some(link) = link {
+        builder.add_metadata_opf(MetadataOpf {
+            name: SOURCE.to_owned(),
+            content: link.href.clone(),
+        });
+    }
+    let content = if Some(true) == instance.download_full_article {
+        download_full_article(link, &mut builder, context).await?
     } else {
         match entry.content {
             Some(Content {
@@ -203,35 +474,46 @@ async fn load_entry(
                 content_type: _,
                 length: _,
                 src: _,
-            }) => {
-                clean_html(
-                    body,
-                    &mut builder,
-                    &base,
-                    client,
-                    server_instance.include_images,
-                    false,
-                    &None,
-                )
-                .await
-            }
+            }) => clean_html(body, &mut builder, base, context, false).await,
             _ => {
-                if Some(false) == server_instance.download_full_article {
-                    return Err(anyhow!(
-                        "No content for {} of {}",
-                        entry.id,
-                        publisher.as_ref()
-                    ));
+                if Some(false) == instance.download_full_article {
+                    return Err(anyhow!("No content for {} of {}", entry.id, publisher));
                 }
-                download_full_article(link, &mut builder, client, Arc::clone(&server_instance))
-                    .await?
+                download_full_article(link, &mut builder, context).await?
             }
         }
     };
+    let language = entry
+        .language
+        .as_deref()
+        .and_then(lang::normalize)
+        .or_else(|| content.lang.clone());
+    if let Some(language) = &language {
+        builder.set_lang(language);
+    }
+    // the direction the article says it has, then that of most of its text,
+    // then that of its language, as feeds often declare a single language for all their entries
+    let dir = content
+        .dir
+        .or(content.text_dir)
+        .or_else(|| language.as_deref().map(language_direction))
+        .or_else(|| text_direction([title.as_str()]));
+    if dir == Some(Direction::Rtl) {
+        builder.epub_direction(PageDirection::Rtl);
+    }
+
+    let content_hash = format!("{:x}", Sha256::digest(&content.html));
+    if instance.update_policy == UpdatePolicy::Content
+        && previous
+            .as_ref()
+            .is_some_and(|previous| previous.content_hash.as_ref() == Some(&content_hash))
+    {
+        return Ok(None);
+    }
+
     let title_page = {
         let entry_href = link.map(|l| l.href.as_str()).unwrap_or("");
-        let publisher_href = find_link(&links).map(|l| l.href.as_str()).unwrap_or("");
-        let publisher = publisher.as_ref();
+        let publisher_href = find_link(links).map(|l| l.href.as_str()).unwrap_or("");
         html! {
             (DOCTYPE)
             html {
@@ -260,57 +542,167 @@ async fn load_entry(
         )
         .map_err(|e| anyhow!(e))?;
     builder
-        .add_content(EpubContent::new("title.html", Cursor::new(title_page.0)))
+        .add_content(EpubContent::new(
+            "title.html",
+            set_lang_dir(&title_page.0, language.as_deref(), dir).as_bytes(),
+        ))
         .map_err(|e| anyhow!(e))?;
+    let article = set_lang_dir(
+        &String::from_utf8_lossy(&content.html),
+        language.as_deref(),
+        dir,
+    );
     builder
-        .add_content(EpubContent::new("article.html", content.as_ref()))
+        .add_content(EpubContent::new("article.html", article.as_bytes()))
         .map_err(|e| anyhow!(e))?;
 
     if let Some(content) = entry.summary {
         builder.add_description(content.content);
     }
 
-    let file = std::fs::File::create(&filename)?;
-    builder.generate(&file).map_err(|e| anyhow!(e))?;
-    let event = json!({
-        "type": "addDocument",
-        "info": {
-            "title": &title,
-            "author": author,
-            "year": year,
-            "publisher": publisher.as_ref(),
-            "identifier": entry.id,
-            "added": Local::now().naive_local(),
-            "file": {
-                "path": path,
-                "kind": "epub",
-                "size": file.metadata().ok().map_or(0, |m| m.len()),
+    // an updated entry replaces its previous document, unless it has been moved elsewhere
+    let previous = previous
+        .map(|previous| previous.path)
+        .filter(|previous| previous.starts_with(save_path));
+    let (filename, replaced) = match previous {
+        Some(previous) if previous.exists() => (previous, true),
+        previous => {
+            if let Some(path) = previous
+                .as_deref()
+                .and_then(|p| p.strip_prefix(library_path.as_path()).ok())
+            {
+                // the previous document is gone, but may still be in the library
+                Event::RemoveDocument { path }.send();
             }
+
+            let mut hasher = Sha256::new();
+            hasher.update(&entry.id);
+            let filename = format!("{}-{:x}.epub", date, hasher.finalize());
+            (save_path.join(filename), false)
+        }
+    };
+    let path = filename.strip_prefix(library_path.as_path())?;
+    let partial = PartialFile::create(&filename)?;
+    builder.generate(partial.file()).map_err(|e| anyhow!(e))?;
+    let file = partial.complete()?;
+    let size = file.metadata().ok().map_or(0, |m| m.len());
+    let info = Info {
+        title: title.clone(),
+        author,
+        year,
+        publisher: publisher.to_string(),
+        identifier: entry.id,
+        language: language.unwrap_or_default(),
+        series: publisher.to_string(),
+        number: number.to_string(),
+        categories,
+        // an updated document keeps when it was first added
+        added: (!replaced).then(|| Local::now().naive_local()),
+        file: FileInfo {
+            path: path.to_path_buf(),
+            kind: "epub".to_owned(),
+            size,
         },
-    });
-    println!("{event}");
-    notify(&format!("Added {title}"));
-    Ok(filename)
+        ..Info::default()
+    };
+    if replaced {
+        Event::UpdateDocument { path, info: &info }.send();
+        notify(&format!("Updated {title}"));
+    } else {
+        Event::AddDocument { info: &info }.send();
+        notify(&format!("Added {title}"));
+    }
+    Ok(Some(Document {
+        path: filename,
+        title,
+        link: link.map(|l| l.href.clone()),
+        feed: publisher.to_string(),
+        content_hash,
+        size,
+    }))
 }
 
 async fn download_full_article(
     link: Option<&Link>,
-    builder: &mut EpubBuilder<ZipLibrary>,
-    client: Client,
-    server_instance: Arc<Instance>,
-) -> Result<Bytes> {
+    builder: &mut EpubBuilder<FileZip>,
+    context: &FeedContext,
+) -> Result<Article> {
     let link = link.ok_or_else(|| anyhow!("No link to download"))?;
 
-    let res = client.get(link.href.as_str()).await?;
+    let res = context
+        .client
+        .get(link.href.as_str(), Resource::Article)
+        .await?;
     let html = clean_html(
-        String::from_utf8(res.body.to_vec())?,
+        decode_html(&res.body, res.content_type.as_ref()),
         builder,
         &Some(link.href.clone()),
-        client,
-        server_instance.include_images,
-        server_instance.enable_filter,
-        &server_instance.filter_element,
+        context,
+        true,
     )
     .await;
     Ok(html)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the entries of an RSS feed with the given `items`
+    fn entries(items: &str) -> Vec<feed_rs::model::Entry> {
+        let feed =
+            format!(r#"<rss version="2.0"><channel><title>t</title>{items}</channel></rss>"#);
+        parse_feed(feed.as_bytes()).unwrap().entries
+    }
+
+    #[test]
+    fn entry_id_prefers_the_configured_identity() {
+        let entry = &entries(
+            "<item><guid>id-1</guid><link>https://example.com/1</link><title>One</title></item>",
+        )[0];
+        assert_eq!(entry_id(entry, EntryId::Id).unwrap(), "id-1");
+        assert_eq!(
+            entry_id(entry, EntryId::Link).unwrap(),
+            "https://example.com/1"
+        );
+        assert_eq!(
+            entry_id(entry, EntryId::Title).unwrap(),
+            entry_id(&entries("<item><title>One</title></item>")[0], EntryId::Id).unwrap()
+        );
+    }
+
+    #[test]
+    fn entry_id_falls_back_to_the_link() {
+        let entry = &entries("<item><link>https://example.com/1</link></item>")[0];
+        assert_eq!(
+            entry_id(entry, EntryId::Id).unwrap(),
+            "https://example.com/1"
+        );
+    }
+
+    #[test]
+    fn entry_id_tells_untitled_entries_apart_by_content() {
+        let entries = entries(
+            "<item><description>first</description></item>\
+            <item><description>second</description></item>",
+        );
+        let first = entry_id(&entries[0], EntryId::Id).unwrap();
+        let second = entry_id(&entries[1], EntryId::Id).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn entry_id_is_the_same_for_every_download() {
+        let items = "<item><description>content</description></item>";
+        assert_eq!(
+            entry_id(&entries(items)[0], EntryId::Id),
+            entry_id(&entries(items)[0], EntryId::Id)
+        );
+    }
+
+    #[test]
+    fn entry_id_without_anything_to_identify() {
+        let entry = &entries("<item><author>someone</author></item>")[0];
+        assert_eq!(entry_id(entry, EntryId::Id), None);
+    }
+}
@@ -1,16 +1,22 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, fs::File, path::Path, str::FromStr};
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use epub_builder::{EpubBuilder, ZipLibrary};
-use futures::future::join_all;
+use epub_builder::EpubBuilder;
+use futures::{stream, StreamExt};
 use lazy_static::lazy_static;
 use mime_guess::{get_mime_extensions, Mime, MimeGuess};
 use regex::{Captures, Regex};
-use scraper::{selectable::Selectable, Html, Selector};
+use scraper::{selectable::Selectable, ElementRef, Html, Selector};
 use url::Url;
 
-use crate::{client::Client, plato::notify};
+use crate::{
+    client::{Client, Resource},
+    epub::FileZip,
+    feed::FeedContext,
+    lang::{self, text_direction, Direction},
+    plato::notify,
+};
 
 lazy_static! {
     static ref CLEAR_SELECTOR: Selector = Selector::parse(
@@ -54,6 +60,42 @@ style"
     };
 }
 
+/// An article cleaned up to be added to an EPUB
+pub struct Article {
+    pub html: Bytes,
+    /// Language of the article, according to the `lang` attribute of its HTML
+    pub lang: Option<String>,
+    /// Direction of the article, according to the `dir` attribute of its HTML
+    pub dir: Option<Direction>,
+    /// Direction of the article, guessed from its text
+    pub text_dir: Option<Direction>,
+}
+
+/// Get the first value of the attributes `names` of `elem` or of its closest ancestor
+/// having one, which `parse` accepts
+fn inherited_attr<T>(
+    elem: ElementRef,
+    names: &[&str],
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<T> {
+    std::iter::once(elem)
+        .chain(elem.ancestors().filter_map(ElementRef::wrap))
+        .find_map(|elem| {
+            names
+                .iter()
+                .find_map(|name| parse(elem.value().attr(name)?))
+        })
+}
+
+/// Get the language, direction, and direction of the text of `elem`
+fn lang_dir(elem: ElementRef) -> (Option<String>, Option<Direction>, Option<Direction>) {
+    (
+        inherited_attr(elem, &["lang", "xml:lang"], lang::normalize),
+        inherited_attr(elem, &["dir"], Direction::from_attr),
+        text_direction(elem.text()),
+    )
+}
+
 fn get_urls<'a, T: Selectable<'a>>(doc: T, base_url: &Option<String>) -> Vec<Url> {
     doc.select(&IMG_SELECTOR)
         .map(|elem| {
@@ -77,15 +119,23 @@ fn get_urls<'a, T: Selectable<'a>>(doc: T, base_url: &Option<String>) -> Vec<Url
         .collect::<Vec<_>>()
 }
 
+/// Clean up the `html` of an article, adding its images to the EPUB being built by `builder`.
+/// Images are downloaded a few at a time to anonymous temporary files in the directory of the
+/// feed, rather than `/tmp`, which is kept in memory on most e-readers, and copied from there
+/// to the EPUB, which is also built on disk.
+/// The article is only filtered down to a single element if `filter` is set, and the instance of
+/// the feed enables it.
 pub async fn clean_html(
     mut html: String,
-    builder: &mut EpubBuilder<ZipLibrary>,
+    builder: &mut EpubBuilder<FileZip>,
     base_url: &Option<String>,
-    client: Client,
-    include_images: bool,
-    enable_filter: bool,
-    filter_element: &Option<String>,
-) -> Bytes {
+    context: &FeedContext,
+    filter: bool,
+) -> Article {
+    let include_images = context.instance.include_images;
+    let enable_filter = filter && context.instance.enable_filter;
+    let filter_element = &context.instance.filter_element;
+    let mut detected = (None, None, None);
     let urls = {
         let mut doc = Html::parse_document(&html);
         let elements_to_clear = doc
@@ -108,6 +158,7 @@ pub async fn clean_html(
                 .chain(FILTER_ELEMENTS.iter())
             {
                 if let Some(elem) = doc.select(filter).next() {
+                    detected = lang_dir(elem);
                     urls = if include_images {
                         Some(get_urls(elem, base_url))
                     } else {
@@ -123,6 +174,7 @@ pub async fn clean_html(
         }
 
         urls.unwrap_or_else(|| {
+            detected = lang_dir(doc.root_element());
             html = doc.html();
             if include_images {
                 get_urls(&doc, base_url)
@@ -134,55 +186,49 @@ pub async fn clean_html(
 
     if urls.len() > 1 {
         notify(&format!("loading {} images", urls.len()));
-    } else if urls.len() > 0 {
+    } else if !urls.is_empty() {
         notify("loading 1 image");
     }
 
-    let tasks = urls
-        .into_iter()
+    let mut downloads = stream::iter(urls)
         .map(|url| {
-            let client = client.clone();
-            tokio::spawn(async move { (url.to_string(), load_img(url, client).await) })
+            let client = context.client.clone();
+            let tmp_dir = context.save_path.as_path();
+            async move { (url.to_string(), load_img(url, client, tmp_dir).await) }
         })
-        .collect::<Vec<_>>();
-    let tasks = join_all(tasks).await;
-
-    let map = tasks
-        .into_iter()
-        .enumerate()
-        .filter_map(|(i, res)| {
-            let (urls, err) = match res {
-                Err(err) => (None, Some(anyhow!(err))),
-                Ok((url, Err(err))) => (Some((url, None)), Some(err)),
-                Ok((url, Ok(img))) => {
-                    let path = format!(
-                        "{i}.{}",
-                        img.ext
-                            .or_else(|| get_mime_extensions(&img.mime)
-                                .and_then(|e| e.first())
-                                .copied()
-                                .map(|x| x.to_owned()))
-                            .unwrap_or_default()
-                    );
+        .buffered(context.concurrent_images.max(1))
+        .enumerate();
 
-                    match builder.add_resource(&path, img.bytes.as_ref(), img.mime.as_ref()) {
-                        Err(err) => (Some((url, None)), Some(anyhow!(err))),
-                        Ok(_) => (Some((url, Some(path))), None),
-                    }
-                }
-            };
-
-            if let Some(err) = err {
+    // each image is added as soon as it is downloaded, closing its temporary file
+    let mut map = HashMap::new();
+    while let Some((i, (url, res))) = downloads.next().await {
+        let img = match res {
+            Ok(img) => img,
+            Err(err) => {
                 eprintln!("feed: {:?}", err);
+                continue;
             }
+        };
 
-            urls
-        })
-        .filter_map(|(a, b)| b.map(|b| (a, b)))
-        .collect::<HashMap<_, _>>();
+        let path = format!(
+            "{i}.{}",
+            img.ext
+                .or_else(|| get_mime_extensions(&img.mime)
+                    .and_then(|e| e.first())
+                    .copied()
+                    .map(|x| x.to_owned()))
+                .unwrap_or_default()
+        );
+        match builder.add_resource(&path, img.file, img.mime.as_ref()) {
+            Err(err) => eprintln!("feed: {:?}", anyhow!(err)),
+            Ok(_) => {
+                map.insert(url, path);
+            }
+        }
+    }
 
     html = CLEAR_REGEX.replace_all(&html, " ").to_string();
-    Bytes::copy_from_slice(
+    let html = Bytes::copy_from_slice(
         IMG_REGEX
             .replace_all(&html, |caps: &Captures| {
                 caps.get(2)
@@ -191,22 +237,32 @@ pub async fn clean_html(
                     .unwrap_or_default()
             })
             .as_bytes(),
-    )
+    );
+
+    let (lang, dir, text_dir) = detected;
+    Article {
+        html,
+        lang,
+        dir,
+        text_dir,
+    }
 }
 
 struct Img {
-    bytes: Bytes,
+    file: File,
     mime: Mime,
     ext: Option<String>,
 }
 
-async fn load_img(url: Url, client: Client) -> Result<Img> {
+/// Download an image to an anonymous temporary file in `tmp_dir`,
+/// so it isn't kept in memory while it is downloaded
+async fn load_img(url: Url, client: Client, tmp_dir: &Path) -> Result<Img> {
     let ext = EXT_REGEX
         .captures(url.path())
         .and_then(|c| c.get(1))
         .map(|m| m.as_str().to_owned());
 
-    let res = client.get(url).await?;
+    let res = client.download(url, Resource::Image, tmp_dir).await?;
     let mime = res
         .content_type
         .and_then(|h| Mime::from_str(h.to_str().ok()?).ok())
@@ -217,7 +273,7 @@ async fn load_img(url: Url, client: Client) -> Result<Img> {
         .ok_or(anyhow!("Failed to get mimetype"))?;
 
     Ok(Img {
-        bytes: res.body,
+        file: res.body,
         mime,
         ext,
     })
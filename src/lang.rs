@@ -0,0 +1,163 @@
+//! Detection of the language and writing direction of articles, so right-to-left articles are
+//! laid out as such.
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+/// Languages written right-to-left, by their primary subtag
+const RTL_LANGUAGES: [&str; 14] = [
+    "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ks", "ps", "sd", "syr", "ug", "ur", "yi",
+];
+/// Scripts written right-to-left, by their script subtag
+const RTL_SCRIPTS: [&str; 6] = ["adlm", "arab", "hebr", "nkoo", "syrc", "thaa"];
+
+lazy_static! {
+    static ref HTML_REGEX: Regex = Regex::new(r"(?i)<html\b([^>]*)>").unwrap();
+    static ref LANG_DIR_REGEX: Regex =
+        Regex::new(r#"(?i)\s(?:xml:)?(?:lang|dir)\s*=\s*(?:"[^"]*"|'[^']*'|[^\s>]+)"#).unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Ltr,
+    Rtl,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Ltr => "ltr",
+            Direction::Rtl => "rtl",
+        }
+    }
+
+    /// Parse the value of a `dir` attribute. `auto` leaves the direction to be detected.
+    pub fn from_attr(dir: &str) -> Option<Self> {
+        match dir.trim().to_ascii_lowercase().as_str() {
+            "ltr" => Some(Direction::Ltr),
+            "rtl" => Some(Direction::Rtl),
+            _ => None,
+        }
+    }
+}
+
+/// Normalize a language tag, such as `en_US`, to its BCP 47 form, `en-US`.
+/// Returns `None` if it isn't a language tag.
+pub fn normalize(lang: &str) -> Option<String> {
+    let lang = lang.trim().replace('_', "-");
+    let valid = !lang.is_empty()
+        && lang
+            .split('-')
+            .all(|tag| !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric()));
+    valid.then_some(lang)
+}
+
+/// Get the direction in which the language `lang` is written
+pub fn language_direction(lang: &str) -> Direction {
+    let mut subtags = lang.split(['-', '_']).map(str::to_ascii_lowercase);
+    let primary = subtags.next().unwrap_or_default();
+    // the script, such as Arab in pa-Arab, takes precedence over the language
+    match subtags.find(|tag| tag.len() == 4 && tag.chars().all(|c| c.is_ascii_alphabetic())) {
+        Some(script) if RTL_SCRIPTS.contains(&script.as_str()) => Direction::Rtl,
+        Some(_) => Direction::Ltr,
+        None if RTL_LANGUAGES.contains(&primary.as_str()) => Direction::Rtl,
+        None => Direction::Ltr,
+    }
+}
+
+/// Get the direction of the character `c`, if it is a letter
+fn char_direction(c: char) -> Option<Direction> {
+    match c {
+        // Hebrew, Arabic, Syriac, Thaana, NKo, Samaritan, Mandaic and their supplements
+        '\u{0590}'..='\u{08FF}'
+        // Hebrew and Arabic presentation forms
+        | '\u{FB1D}'..='\u{FDFF}'
+        | '\u{FE70}'..='\u{FEFF}'
+        // Adlam, and other scripts of the Supplementary Multilingual Plane written right-to-left
+        | '\u{10800}'..='\u{10FFF}'
+        | '\u{1E800}'..='\u{1EFFF}' => Some(Direction::Rtl),
+        c if c.is_alphabetic() => Some(Direction::Ltr),
+        _ => None,
+    }
+}
+
+/// Guess the direction of a `text` from the direction of most of its letters.
+/// Returns `None` if it has no letters.
+pub fn text_direction<'a, I: IntoIterator<Item = &'a str>>(text: I) -> Option<Direction> {
+    let (mut ltr, mut rtl) = (0usize, 0usize);
+    for c in text.into_iter().flat_map(str::chars) {
+        match char_direction(c) {
+            Some(Direction::Ltr) => ltr += 1,
+            Some(Direction::Rtl) => rtl += 1,
+            None => (),
+        }
+    }
+
+    match (ltr, rtl) {
+        (0, 0) => None,
+        (ltr, rtl) if rtl > ltr => Some(Direction::Rtl),
+        _ => Some(Direction::Ltr),
+    }
+}
+
+/// Set the `lang`, `xml:lang` and `dir` attributes of the `<html>` element of `html`,
+/// replacing the ones it already has. XHTML readers only look at `xml:lang`.
+pub fn set_lang_dir(html: &str, lang: Option<&str>, dir: Option<Direction>) -> String {
+    HTML_REGEX
+        .replacen(html, 1, |caps: &Captures| {
+            let mut attrs = LANG_DIR_REGEX.replace_all(&caps[1], "").into_owned();
+            if let Some(lang) = lang {
+                attrs.push_str(&format!(r#" lang="{lang}" xml:lang="{lang}""#));
+            }
+            if let Some(dir) = dir {
+                attrs.push_str(&format!(r#" dir="{}""#, dir.as_str()));
+            }
+            format!("<html{attrs}>")
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_tags() {
+        assert_eq!(normalize(" en_US ").as_deref(), Some("en-US"));
+        assert_eq!(normalize("pa-Arab").as_deref(), Some("pa-Arab"));
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("en--US"), None);
+        assert_eq!(normalize("English (US)"), None);
+    }
+
+    #[test]
+    fn direction_of_languages() {
+        assert_eq!(language_direction("ar"), Direction::Rtl);
+        assert_eq!(language_direction("he-IL"), Direction::Rtl);
+        assert_eq!(language_direction("pa-Arab"), Direction::Rtl);
+        assert_eq!(language_direction("az-Latn"), Direction::Ltr);
+        assert_eq!(language_direction("en"), Direction::Ltr);
+    }
+
+    #[test]
+    fn direction_of_text() {
+        assert_eq!(text_direction(["مرحبا بالعالم"]), Some(Direction::Rtl));
+        assert_eq!(text_direction(["שלום עולם", " hi"]), Some(Direction::Rtl));
+        assert_eq!(text_direction(["Hello, عالم"]), Some(Direction::Ltr));
+        assert_eq!(text_direction(["12:30 !"]), None);
+        assert_eq!(text_direction([]), None);
+    }
+
+    #[test]
+    fn set_lang_dir_replaces_attributes() {
+        let html = r#"<html class="a" lang=en dir='ltr' xml:lang="en"><body></body></html>"#;
+        assert_eq!(
+            set_lang_dir(html, Some("ar"), Some(Direction::Rtl)),
+            r#"<html class="a" lang="ar" xml:lang="ar" dir="rtl"><body></body></html>"#
+        );
+        assert_eq!(
+            set_lang_dir(html, None, None),
+            r#"<html class="a"><body></body></html>"#
+        );
+    }
+}
@@ -1,33 +1,79 @@
 mod args;
+mod charset;
 mod client;
+mod cookies;
 mod db;
+mod epub;
 mod feed;
 mod html;
+mod lang;
+mod persist;
 mod plato;
+mod reconcile;
 mod settings;
+mod shutdown;
 
-use std::{fs, sync::Arc};
+use std::{cmp::min, fs, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
 use args::Args;
 use client::Client;
 use db::Db;
-use feed::{load_feed, program_name};
+use feed::{load_feed, program_name, remove_expired, remove_finished};
 use futures::future::join_all;
-use plato::notify;
+use plato::{notify, Event, Messages};
+use reconcile::reconcile;
 use settings::Settings;
+use tokio::{sync::Semaphore, time};
 
 async fn run() -> Result<()> {
     let args = Args::new()?;
     let settings = Settings::load().with_context(|| "failed to load settings")?;
+    let network = settings.network;
+    // only turn Wi-Fi back off if it was turned on by the hook
+    let enable_wifi = !args.online && !args.wifi && network.enable_wifi;
+    if enable_wifi {
+        Event::SetWifi { enable: true }.send();
+    }
+
+    let res = update(args, settings).await;
+    if enable_wifi && network.disable_wifi {
+        Event::SetWifi { enable: false }.send();
+    }
+    res
+}
+
+/// Wait until the network is up, or until `timeout` has passed, in which case `false` is returned
+async fn wait_network(messages: &mut Messages, timeout: Option<Duration>) -> bool {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, messages.network_up()).await.is_ok(),
+        None => {
+            messages.network_up().await;
+            true
+        }
+    }
+}
+
+async fn update(args: Args, settings: Settings) -> Result<()> {
+    let shutdown = shutdown::listen()?;
+    let mut messages = plato::messages();
     if !args.online {
-        if !args.wifi {
+        if !args.wifi && !settings.network.enable_wifi {
             plato::notify("Please enable WiFi to update feeds");
         } else {
             plato::notify("Waiting for the network to come up");
         }
-        let mut line = String::new();
-        std::io::stdin().read_line(&mut line)?;
+
+        let timeout = settings.network.network_timeout.map(Duration::from_secs);
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            up = wait_network(&mut messages, timeout) => {
+                if !up {
+                    notify("The network didn't come up, feeds were not updated");
+                    return Ok(());
+                }
+            }
+        };
     }
 
     if !args.save_path.exists() {
@@ -35,11 +81,36 @@ async fn run() -> Result<()> {
     }
 
     let db = Arc::new(Db::new()?);
-    let client = Client::new(program_name(), settings.concurrent_requests)?;
+    let client = Client::new(
+        program_name(),
+        settings.concurrent_requests,
+        &settings.ca_certificates,
+        shutdown.clone(),
+    )?;
     let library_path = Arc::new(args.library_path);
+    let concurrent_images = settings.concurrent_images;
+    let entries = Arc::new(Semaphore::new(min(
+        settings.concurrent_entries.max(1),
+        Semaphore::MAX_PERMITS,
+    )));
+
+    let reconcile_settings = settings.reconcile;
+    let servers = settings
+        .flatten_servers(args.save_path)
+        .into_iter()
+        .collect::<Vec<_>>();
+    let dirs = servers
+        .iter()
+        .map(|server| (server.path.as_str(), server.dir.as_path()))
+        .collect::<Vec<_>>();
+    tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        _ = reconcile(&db, &mut messages, &dirs, &reconcile_settings, &library_path) => (),
+    }
 
-    let mut tasks = Vec::with_capacity(settings.servers.len());
-    for server in settings.flatten_servers(args.save_path) {
+    let mut tasks = Vec::with_capacity(servers.len());
+    let mut feeds = Vec::with_capacity(servers.len());
+    for server in servers {
         if !server.dir.exists() {
             let res = fs::create_dir_all(&server.dir)
                 .with_context(|| format!("creating server directory: {}", server.dir.display()));
@@ -50,34 +121,41 @@ async fn run() -> Result<()> {
             }
         }
 
+        let client = match client.for_instance(&server.instance) {
+            Ok(client) => client,
+            Err(err) => {
+                let err = err.context(format!("creating client for {}", server.server));
+                notify(&err.to_string());
+                eprintln!("feed: {:?}", err);
+                continue;
+            }
+        };
+
+        let path = server.path.clone();
+        let dir = server.dir.clone();
+        let retention = server.instance.retention;
+        let name = server.server.clone();
         let db = Arc::clone(&db);
-        let instance = Arc::new(server.instance);
-        let client = client.clone();
         let library_path = Arc::clone(&library_path);
-        let save_dir = Arc::new(server.dir);
-        let server = Arc::new(server.server);
+        let entries = Arc::clone(&entries);
         let task = tokio::spawn(async move {
-            load_feed(
-                db,
-                Arc::clone(&server),
-                instance,
-                client,
-                library_path,
-                save_dir,
-            )
-            .await
-            .with_context(|| format!("Server {}", server))
+            load_feed(db, server, client, library_path, entries, concurrent_images)
+                .await
+                .with_context(|| format!("Server {}", name))
         });
-        tasks.push(task);
+        tasks.push((path, task));
+        feeds.push((dir, retention));
     }
 
     let mut errors = 0;
-    for result in join_all(tasks).await {
+    let (paths, tasks): (Vec<_>, Vec<_>) = tasks.into_iter().unzip();
+    for (path, result) in paths.iter().zip(join_all(tasks).await) {
         let err = match result {
             Err(e) => e.into(),
             Ok(Err(e)) => e,
-            Ok(Ok(tasks)) => {
-                for result in join_all(tasks).await {
+            Ok(Ok(feed)) => {
+                let mut feed_errors = 0;
+                for result in join_all(feed.tasks).await {
                     let err = match result {
                         Err(e) => e.into(),
                         Ok(Err(e)) => e,
@@ -85,9 +163,17 @@ async fn run() -> Result<()> {
                     };
 
                     eprintln!("feed: {:?}", err);
-                    errors += 1;
+                    feed_errors += 1;
+                }
+
+                match feed.validators {
+                    Some(validators) if feed_errors == 0 => {
+                        db.set_validators(path, validators);
+                    }
+                    _ => (),
                 }
 
+                errors += feed_errors;
                 continue;
             }
         };
@@ -96,6 +182,35 @@ async fn run() -> Result<()> {
         errors += 1;
     }
 
+    if shutdown.is_cancelled() {
+        eprintln!("feed: cancelled with {errors} errors");
+        return Ok(());
+    }
+
+    let mut removed = 0;
+    // once Plato fails to answer, don't make every other feed wait for it too
+    let mut search = true;
+    for (path, (dir, retention)) in paths.iter().zip(feeds) {
+        match retention.remove_finished {
+            Some(days) if search => {
+                let res =
+                    remove_finished(&db, &mut messages, path, &dir, days, &library_path).await;
+                match res {
+                    Ok(count) => removed += count,
+                    Err(err) => {
+                        eprintln!("feed: {:?}", err);
+                        search = false;
+                    }
+                }
+            }
+            _ => (),
+        }
+        removed += remove_expired(&db, path, &dir, &retention, &library_path);
+    }
+    if removed > 0 {
+        notify(&format!("Removed {removed} old articles"));
+    }
+
     if errors > 0 {
         notify(&format!("Feed downloaded with {errors} errors"));
     } else {
@@ -0,0 +1,84 @@
+//! Crash-safe writing of files. Files are written to a temporary file next to their destination,
+//! which is only renamed to the destination once it is complete, so a power loss or a killed
+//! process never leaves a truncated file behind.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+/// Extension of the files being written
+pub const PARTIAL_EXTENSION: &str = "part";
+
+/// A file being written, which is removed if it is dropped before being completed,
+/// so no partially written files are left behind on errors or cancellation
+pub struct PartialFile {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: File,
+    complete: bool,
+}
+
+impl PartialFile {
+    /// Start writing the file at `path`
+    pub fn create(path: &Path) -> Result<PartialFile> {
+        let mut tmp = OsString::from(path.as_os_str());
+        tmp.push(".");
+        tmp.push(PARTIAL_EXTENSION);
+        let tmp = PathBuf::from(tmp);
+        let file = File::create(&tmp)?;
+        Ok(PartialFile {
+            path: path.to_path_buf(),
+            tmp,
+            file,
+            complete: false,
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Make sure the content is written to the disk, then move it to its destination
+    pub fn complete(mut self) -> Result<File> {
+        self.file.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        self.complete = true;
+        if let Some(dir) = self.path.parent().and_then(|dir| File::open(dir).ok()) {
+            // make sure the rename itself is on the disk; not every file system supports it
+            let _ = dir.sync_all();
+        }
+
+        Ok(self.file.try_clone()?)
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if self.complete {
+            return;
+        }
+
+        if let Err(err) = fs::remove_file(&self.tmp) {
+            eprintln!("feed: {:?}", err);
+        }
+    }
+}
+
+/// Replace the file at `path` with what `write` writes
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<&File>) -> Result<()>,
+{
+    let partial = PartialFile::create(path)?;
+    let mut writer = BufWriter::new(partial.file());
+    write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    partial.complete()?;
+    Ok(())
+}
@@ -1,12 +1,312 @@
-//! Helper functions for interacting with the Plato e-reader software.
+//! The protocol of Plato's hooks: events are sent to Plato as JSON lines on stdout,
+//! and Plato sends messages back as JSON lines on stdin.
 
-use serde_json::json;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io::BufRead,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time};
+
+/// How long to wait for Plato to answer a search
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Formats of the dates in the metadata of Plato's library
+const DATE_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S%.f"];
+
+/// Events understood by Plato
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event<'a> {
+    /// Show a notification on the device
+    Notify { message: &'a str },
+    /// Add a document to the library
+    AddDocument { info: &'a Info },
+    /// Update the metadata of the document of the library at `path`.
+    /// The fields of `info` which aren't sent, such as when it was added and its reading
+    /// progress, are left as they are.
+    UpdateDocument { path: &'a Path, info: &'a Info },
+    /// Remove the document at `path` from the library
+    RemoveDocument { path: &'a Path },
+    /// Search the library for the documents in the directory at `path`.
+    /// Plato answers with a [Message::Search].
+    Search { path: &'a Path },
+    /// Turn Wi-Fi on or off
+    SetWifi { enable: bool },
+    /// Remove the documents which no longer exist from the library
+    CleanUp,
+}
+
+impl Event<'_> {
+    pub fn send(&self) {
+        match serde_json::to_string(self) {
+            Ok(event) => println!("{event}"),
+            Err(err) => eprintln!("feed: {:?}", anyhow!(err).context(format!("{self:?}"))),
+        }
+    }
+}
+
+/// Messages sent by Plato
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Message {
+    /// The network went up or down
+    Network { status: NetworkStatus },
+    /// The results of an [Event::Search]
+    Search {
+        #[serde(default)]
+        results: Vec<Info>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NetworkStatus {
+    Up,
+    Down,
+}
+
+/// The metadata of a document of Plato's library
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Info {
+    pub title: String,
+    // blank fields aren't sent, so updating a document keeps the metadata Plato already has
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub author: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub year: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub publisher: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub identifier: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub language: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub series: String,
+    /// Number of the document in its series
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub number: String,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub categories: BTreeSet<String>,
+    /// When the document was added to the library.
+    /// Plato knows better, so it isn't read from its messages.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub added: Option<NaiveDateTime>,
+    pub file: FileInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reader: Option<ReaderInfo>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FileInfo {
+    /// Path of the document, relative to the library
+    pub path: PathBuf,
+    pub kind: String,
+    pub size: u64,
+}
+
+/// The reading state of a document
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ReaderInfo {
+    pub finished: bool,
+    /// When the document was last opened
+    opened: Option<String>,
+}
+
+impl ReaderInfo {
+    pub fn opened(&self) -> Option<NaiveDateTime> {
+        let opened = self.opened.as_deref()?;
+        DATE_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(opened, format).ok())
+    }
+}
 
 /// Show a notification on the device with the given `message`.
 pub fn notify(message: &str) {
-    let event = json!({
-        "type": "notify",
-        "message": message,
+    Event::Notify { message }.send();
+}
+
+/// The messages Plato sends to the hook on stdin
+pub struct Messages {
+    rx: mpsc::UnboundedReceiver<Message>,
+    /// Messages received while waiting for another kind of message, to be received next
+    pending: VecDeque<Message>,
+}
+
+/// Start receiving the messages Plato sends to the hook.
+/// A blocking read can't be cancelled, so stdin is read from a thread which is left behind on exit.
+pub fn messages() -> Messages {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    eprintln!("feed: {:?}", err);
+                    break;
+                }
+            };
+
+            match serde_json::from_str(&line) {
+                Ok(message) => {
+                    if tx.send(message).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => eprintln!("feed: {:?}", anyhow!(err).context(line)),
+            }
+        }
     });
-    println!("{event}");
+
+    Messages::new(rx)
+}
+
+impl Messages {
+    fn new(rx: mpsc::UnboundedReceiver<Message>) -> Messages {
+        Messages {
+            rx,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Wait for the next message. Returns `None` once stdin is closed.
+    pub async fn recv(&mut self) -> Option<Message> {
+        match self.pending.pop_front() {
+            Some(message) => Some(message),
+            None => self.rx.recv().await,
+        }
+    }
+
+    /// Wait for the network to come up. Returns `false` if stdin is closed first.
+    pub async fn network_up(&mut self) -> bool {
+        while let Some(message) = self.recv().await {
+            if let Message::Network {
+                status: NetworkStatus::Up,
+            } = message
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Get the documents of Plato's library in the directory at `path`.
+    /// The other messages received in the meantime are kept, to be received next.
+    pub async fn search(&mut self, path: &Path) -> Result<Vec<Info>> {
+        Event::Search { path }.send();
+        time::timeout(SEARCH_TIMEOUT, async {
+            while let Some(message) = self.rx.recv().await {
+                match message {
+                    Message::Search { results } => return Ok(results),
+                    Message::Unknown => (),
+                    message => self.pending.push_back(message),
+                }
+            }
+
+            Err(anyhow!("stdin closed while searching {}", path.display()))
+        })
+        .await
+        .map_err(|_| anyhow!("Plato didn't answer the search of {}", path.display()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(message: &str) -> Message {
+        serde_json::from_str(message).unwrap()
+    }
+
+    #[test]
+    fn network_messages() {
+        assert!(matches!(
+            parse(r#"{"type": "network", "status": "up"}"#),
+            Message::Network {
+                status: NetworkStatus::Up
+            }
+        ));
+        assert!(matches!(
+            parse(r#"{"type": "network", "status": "down"}"#),
+            Message::Network {
+                status: NetworkStatus::Down
+            }
+        ));
+    }
+
+    #[test]
+    fn search_messages() {
+        let message = parse(
+            r#"{"type": "search", "results": [{"title": "Title", "file": {"path": "a.epub"},
+            "reader": {"finished": true, "opened": "2024-01-02 03:04:05"}}]}"#,
+        );
+        let Message::Search { results } = message else {
+            panic!("not a search: {message:?}");
+        };
+        assert_eq!(results[0].title, "Title");
+        assert_eq!(results[0].file.path, PathBuf::from("a.epub"));
+        let reader = results[0].reader.as_ref().unwrap();
+        assert!(reader.finished);
+        assert!(reader.opened().is_some());
+
+        let message = parse(r#"{"type": "search"}"#);
+        assert!(matches!(message, Message::Search { results } if results.is_empty()));
+    }
+
+    #[test]
+    fn unknown_messages() {
+        assert!(matches!(
+            parse(r#"{"type": "somethingNew", "value": 1}"#),
+            Message::Unknown
+        ));
+    }
+
+    #[tokio::test]
+    async fn search_keeps_other_messages() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut messages = Messages::new(rx);
+        tx.send(Message::Network {
+            status: NetworkStatus::Up,
+        })
+        .unwrap();
+        tx.send(Message::Search {
+            results: Vec::new(),
+        })
+        .unwrap();
+        drop(tx);
+
+        assert!(messages.search(Path::new("feed")).await.unwrap().is_empty());
+        assert!(messages.network_up().await);
+    }
+
+    #[test]
+    fn update_document_only_sends_given_fields() {
+        let info = Info {
+            title: "Title".to_owned(),
+            publisher: "Feed".to_owned(),
+            ..Info::default()
+        };
+        let event = Event::UpdateDocument {
+            path: Path::new("feed/entry.epub"),
+            info: &info,
+        };
+        let event: serde_json::Value = serde_json::to_value(&event).unwrap();
+        let info = event["info"].as_object().unwrap();
+        assert_eq!(info["title"], "Title");
+        assert_eq!(info["publisher"], "Feed");
+        for field in ["author", "categories", "added", "reader"] {
+            assert!(!info.contains_key(field), "{field} was sent");
+        }
+    }
 }
@@ -0,0 +1,189 @@
+//! Reconciliation of the saved entries with the files in the directories of the feeds and with
+//! Plato's library, for when they have been changed by something other than the hook, such as
+//! a file deleted by hand, or a library index which lost track of a document.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+
+use crate::{
+    db::{Db, Record},
+    persist::PARTIAL_EXTENSION,
+    plato::{Event, FileInfo, Info, Messages},
+    settings::{MissingFiles, OrphanFiles, ReconcileSettings},
+};
+
+fn remove_file(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        eprintln!(
+            "feed: {:?}",
+            anyhow!(err).context(format!("removing {}", path.display()))
+        );
+    }
+}
+
+/// Handle the entries of the feed of the server at `path` whose document is missing.
+/// Returns whether any document is missing.
+fn missing_files(db: &Db, path: &str, records: &[Record], missing_files: MissingFiles) -> bool {
+    if missing_files == MissingFiles::Keep {
+        return false;
+    }
+
+    let mut missing = false;
+    for record in records.iter().filter(|record| !record.path.exists()) {
+        missing = true;
+        match missing_files {
+            MissingFiles::Keep => (),
+            MissingFiles::Download => db.forget(path, &record.id),
+            MissingFiles::Forget => {
+                db.remove(path, &record.path);
+            }
+        }
+    }
+
+    missing
+}
+
+/// Handle the files in `dir` which aren't the document of any entry.
+/// Files left behind by an interrupted write are always deleted.
+fn orphan_files(
+    db: &Db,
+    path: &str,
+    dir: &Path,
+    documents: &mut HashSet<PathBuf>,
+    orphan_files: OrphanFiles,
+    library_path: &Path,
+) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let file = entry.path();
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || documents.contains(&file) {
+            continue;
+        }
+
+        match file.extension().and_then(|ext| ext.to_str()) {
+            Some(PARTIAL_EXTENSION) => remove_file(&file),
+            Some("epub") => match orphan_files {
+                OrphanFiles::Keep => (),
+                OrphanFiles::Adopt => {
+                    let modified = metadata.modified().map_or_else(|_| Utc::now(), Into::into);
+                    documents.insert(file.clone());
+                    db.adopt(path, file, modified, metadata.len());
+                }
+                OrphanFiles::Delete => {
+                    remove_file(&file);
+                    if let Ok(path) = file.strip_prefix(library_path) {
+                        Event::RemoveDocument { path }.send();
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Add the documents of `records` which are missing from Plato's library back to it
+async fn check_library(
+    messages: &mut Messages,
+    dir: &Path,
+    records: &[Record],
+    library_path: &Path,
+) -> Result<()> {
+    let library = messages
+        .search(dir)
+        .await?
+        .into_iter()
+        .map(|info| library_path.join(info.file.path))
+        .collect::<HashSet<_>>();
+
+    for record in records {
+        if library.contains(&record.path) || !record.path.exists() {
+            continue;
+        }
+
+        let Ok(path) = record.path.strip_prefix(library_path) else {
+            continue;
+        };
+        let size = record.path.metadata().map_or(0, |m| m.len());
+        let last_update: DateTime<Local> = record.last_update.into();
+        let info = Info {
+            title: record.title.clone().unwrap_or_else(|| record.id.clone()),
+            year: record.last_update.format("%Y").to_string(),
+            publisher: record.feed.clone().unwrap_or_default(),
+            identifier: record.id.clone(),
+            series: record.feed.clone().unwrap_or_default(),
+            number: record.number.map(|n| n.to_string()).unwrap_or_default(),
+            added: Some(last_update.naive_local()),
+            file: FileInfo {
+                path: path.to_path_buf(),
+                kind: "epub".to_owned(),
+                size,
+            },
+            ..Info::default()
+        };
+        Event::AddDocument { info: &info }.send();
+    }
+
+    Ok(())
+}
+
+/// Reconcile the entries of the `feeds`, given as the paths of their servers and their
+/// directories, with the files on disk and Plato's library
+pub async fn reconcile(
+    db: &Db,
+    messages: &mut Messages,
+    feeds: &[(&str, &Path)],
+    settings: &ReconcileSettings,
+    library_path: &Path,
+) {
+    let mut documents = db.documents();
+    // once Plato fails to answer, don't make every other feed wait for it too
+    let mut check = settings.check_library;
+    let mut missing = false;
+    for &(path, dir) in feeds {
+        missing |= missing_files(db, path, &db.records(path), settings.missing_files);
+
+        let res = orphan_files(
+            db,
+            path,
+            dir,
+            &mut documents,
+            settings.orphan_files,
+            library_path,
+        );
+        if let Err(err) = res {
+            eprintln!(
+                "feed: {:?}",
+                err.context(format!("looking for orphan files in {}", dir.display()))
+            );
+        }
+
+        if check {
+            let records = db.records(path);
+            if let Err(err) = check_library(messages, dir, &records, library_path).await {
+                eprintln!("feed: {:?}", err);
+                check = false;
+            }
+        }
+    }
+
+    // let Plato forget the missing documents too
+    if missing {
+        Event::CleanUp.send();
+    }
+}
@@ -15,15 +15,39 @@ const SETTINGS_PATH: &str = "Settings.toml";
 pub struct Settings {
     /// Number of concurrent HTTP Requests to make
     pub concurrent_requests: usize,
+    /// Number of entries to download and build into EPUBs concurrently.
+    /// The text of each entry being built is kept in memory, while its images are written to
+    /// temporary files in the directory of its feed.
+    pub concurrent_entries: usize,
+    /// Number of images of an entry to download concurrently
+    pub concurrent_images: usize,
     /// Whether files should be placed in a directory named after the server they have been pulled
     /// from.
     pub use_server_name_directories: bool,
+    /// Paths to PEM files of extra certificate authorities to trust, such as those of
+    /// self-hosted servers with self-signed certificates
+    pub ca_certificates: Vec<PathBuf>,
     /// Mapping of server names to their respective [Instance] settings.
     pub servers: HashMap<String, InstanceDirectory>,
+    /// Defaults for the [RequestSettings] of every [Instance]
+    #[serde(flatten)]
+    pub requests: RequestSettings,
+    /// Defaults for the [RetentionSettings] of every [Instance]
+    #[serde(flatten)]
+    pub retention: RetentionSettings,
+    /// How the saved entries are reconciled with the files on disk and Plato's library
+    #[serde(flatten)]
+    pub reconcile: ReconcileSettings,
+    /// How the network is brought up when the hook is started offline
+    #[serde(flatten)]
+    pub network: NetworkSettings,
 }
 
 pub struct Server {
     pub server: String,
+    /// Names of the directories containing the server and of the server, joined with `/`.
+    /// Unlike [Server::server], unique among all servers.
+    pub path: String,
     pub dir: PathBuf,
     pub instance: Instance,
 }
@@ -31,23 +55,33 @@ pub struct Server {
 fn flatten_servers_helper<P: AsRef<Path>>(
     output: &mut Vec<Server>,
     server: String,
+    parent: &str,
     prefix: P,
     instance_dir: InstanceDirectory,
     use_server_name_directories: bool,
+    requests: &RequestSettings,
 ) {
+    let path = if parent.is_empty() {
+        server.clone()
+    } else {
+        format!("{parent}/{server}")
+    };
     match instance_dir {
         InstanceDirectory::Directory(children) => {
             for (key, value) in children {
                 flatten_servers_helper(
                     output,
                     key,
+                    &path,
                     prefix.as_ref().join(&server),
                     value,
                     use_server_name_directories,
+                    requests,
                 );
             }
         }
-        InstanceDirectory::Instance(instance) => {
+        InstanceDirectory::Instance(mut instance) => {
+            instance.requests = instance.requests.or(requests);
             let dir = if use_server_name_directories {
                 prefix.as_ref().join(&server)
             } else {
@@ -55,8 +89,9 @@ fn flatten_servers_helper<P: AsRef<Path>>(
             };
             output.push(Server {
                 server,
+                path,
                 dir,
-                instance,
+                instance: *instance,
             })
         }
     }
@@ -77,11 +112,16 @@ impl Settings {
             flatten_servers_helper(
                 &mut output,
                 server,
+                "",
                 &root,
                 instance_dir,
                 self.use_server_name_directories,
+                &self.requests,
             );
         }
+        for server in &mut output {
+            server.instance.retention = server.instance.retention.or(&self.retention);
+        }
 
         output
     }
@@ -91,8 +131,15 @@ impl Default for Settings {
     fn default() -> Self {
         Self {
             concurrent_requests: 5,
+            concurrent_entries: 2,
+            concurrent_images: 2,
             use_server_name_directories: true,
+            ca_certificates: Vec::new(),
             servers: HashMap::new(),
+            requests: RequestSettings::default(),
+            retention: RetentionSettings::default(),
+            reconcile: ReconcileSettings::default(),
+            network: NetworkSettings::default(),
         }
     }
 }
@@ -102,7 +149,7 @@ impl Default for Settings {
 #[serde(untagged)]
 pub enum InstanceDirectory {
     Directory(HashMap<String, InstanceDirectory>),
-    Instance(Instance),
+    Instance(Box<Instance>),
 }
 
 /// Holds the settings for a single instance of a server.
@@ -183,6 +230,55 @@ pub struct Instance {
     /// - `None` leaves the title page image-less
     /// - `Some(img)` copies the `img` file to the EPUB for each entry of the feed
     pub title_img: Option<PathBuf>,
+
+    /// Credentials to authenticate to the server with HTTP Basic authentication
+    pub basic_auth: Option<BasicAuth>,
+
+    /// A token to authenticate to the server with HTTP Bearer authentication
+    pub bearer_token: Option<String>,
+
+    /// Extra HTTP headers to send to the server, such as an API key
+    /// Example:
+    /// ```toml
+    /// headers = { "X-Api-Key" = "secret" }
+    /// ```
+    pub headers: HashMap<String, String>,
+
+    /// The user agent to send instead of the default `plato-feed/<version>`
+    pub user_agent: Option<String>,
+
+    /// The path to a Netscape cookies.txt file, such as one exported from a desktop browser.
+    /// Its cookies are sent with the requests for the feed and its entries, and the cookies set
+    /// by the servers are saved back to it, keeping logged-in sessions alive.
+    pub cookies: Option<PathBuf>,
+
+    /// Whether to accept invalid TLS certificates from the servers of this instance, such as
+    /// expired ones, or self-signed ones not in [Settings::ca_certificates].
+    /// This makes the connections insecure, so it should only be used as a last resort.
+    pub accept_invalid_certs: bool,
+
+    /// Whether the authentication ([Instance::basic_auth], [Instance::bearer_token] and
+    /// [Instance::headers]) is also sent with the requests for the full articles and images of
+    /// the entries in the feed, rather than just the request for the feed itself.
+    /// It is only ever sent to the origin (scheme, host and port) of [Instance::url].
+    /// The default is `false`, as those are often hosted elsewhere.
+    pub authenticate_entries: bool,
+
+    /// What identifies an entry of the feed, to know whether it has already been downloaded.
+    /// The default is [EntryId::Id]
+    pub entry_id: EntryId,
+
+    /// When the entries of the feed which were already downloaded are downloaded again.
+    /// The default is [UpdatePolicy::Updated]
+    pub update_policy: UpdatePolicy,
+
+    /// Overrides the top-level [RequestSettings] for this instance
+    #[serde(flatten)]
+    pub requests: RequestSettings,
+
+    /// Overrides the top-level [RetentionSettings] for this instance
+    #[serde(flatten)]
+    pub retention: RetentionSettings,
 }
 
 impl Default for Instance {
@@ -195,6 +291,213 @@ impl Default for Instance {
             filter_element: None,
             default_author: None,
             title_img: None,
+            basic_auth: None,
+            bearer_token: None,
+            headers: HashMap::new(),
+            user_agent: None,
+            cookies: None,
+            accept_invalid_certs: false,
+            authenticate_entries: false,
+            entry_id: EntryId::default(),
+            update_policy: UpdatePolicy::default(),
+            requests: RequestSettings::default(),
+            retention: RetentionSettings::default(),
+        }
+    }
+}
+
+/// How the entries of a feed are identified
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryId {
+    /// The id given by the feed, falling back to [EntryId::Link] for entries without one
+    #[default]
+    Id,
+    /// The link to the entry, for feeds whose ids change between downloads.
+    /// Falls back to [EntryId::Title] for entries without a link
+    Link,
+    /// The title and publication date of the entry, for feeds without stable ids nor links
+    Title,
+}
+
+/// When entries which were already downloaded are downloaded again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdatePolicy {
+    /// Never download entries again
+    Never,
+    /// Download entries again when the feed says they were updated,
+    /// but only replace their document if their content changed
+    Content,
+    /// Download entries again and replace their document whenever the feed says they were updated
+    #[default]
+    Updated,
+}
+
+/// Credentials for HTTP Basic authentication
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// Settings for how HTTP requests are made. Each can be set at the top level of the settings,
+/// and overridden by each [Instance]. Unset values fall back to the defaults of the client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RequestSettings {
+    /// Number of seconds to wait for a connection to a server to be established
+    pub connect_timeout: Option<u64>,
+
+    /// Number of seconds to wait for a server to send a response, or the next part of it
+    pub read_timeout: Option<u64>,
+
+    /// Number of times to retry a request that failed with a transient error:
+    /// a timeout, a server error (5xx), or too many requests (429)
+    pub max_retries: Option<u32>,
+
+    /// Number of seconds to wait before retrying a request. The delay doubles with each retry,
+    /// unless the server specifies how long to wait with a `Retry-After` header.
+    pub retry_delay: Option<u64>,
+
+    /// Number of concurrent HTTP requests to make to a single host, within the limit of
+    /// [Settings::concurrent_requests]. Hosts are shared between instances, so the lowest limit
+    /// of the instances making requests to a host applies to it.
+    pub host_requests: Option<usize>,
+
+    /// Number of milliseconds to wait between starting requests to the same host.
+    /// The longest delay of the instances making requests to a host applies to it.
+    pub host_delay: Option<u64>,
+
+    /// The URL of an HTTP, HTTPS or SOCKS5 proxy to make requests through,
+    /// e.g. `socks5://127.0.0.1:1080`.
+    /// An empty string makes requests directly, overriding the top-level proxy.
+    pub proxy: Option<String>,
+
+    /// Maximum size of a feed, in kibibytes. Larger feeds are not downloaded.
+    pub max_feed_size: Option<usize>,
+
+    /// Maximum size of the page of a full article, in kibibytes.
+    /// Larger articles are not downloaded.
+    pub max_article_size: Option<usize>,
+
+    /// Maximum size of an image, in kibibytes.
+    /// Larger images are left out of the article.
+    pub max_image_size: Option<usize>,
+}
+
+impl RequestSettings {
+    /// Fill in the values not set in `self` with those of `defaults`
+    fn or(self, defaults: &RequestSettings) -> RequestSettings {
+        RequestSettings {
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+            read_timeout: self.read_timeout.or(defaults.read_timeout),
+            max_retries: self.max_retries.or(defaults.max_retries),
+            retry_delay: self.retry_delay.or(defaults.retry_delay),
+            host_requests: self.host_requests.or(defaults.host_requests),
+            host_delay: self.host_delay.or(defaults.host_delay),
+            proxy: self.proxy.or_else(|| defaults.proxy.clone()),
+            max_feed_size: self.max_feed_size.or(defaults.max_feed_size),
+            max_article_size: self.max_article_size.or(defaults.max_article_size),
+            max_image_size: self.max_image_size.or(defaults.max_image_size),
+        }
+    }
+}
+
+/// Settings for how long the documents of the entries of a feed are kept. Each can be set at the
+/// top level of the settings, and overridden by each [Instance]; they apply to each feed
+/// separately. Documents are kept while all of the set rules allow it.
+/// Documents which have been moved out of the directory of their feed are left alone.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RetentionSettings {
+    /// Number of the most recent entries to keep
+    pub keep_entries: Option<usize>,
+
+    /// Number of days to keep entries for, since they were last updated
+    pub keep_days: Option<u32>,
+
+    /// Maximum total size of the documents of the feed, in mebibytes.
+    /// The oldest entries are removed first.
+    pub keep_size: Option<u64>,
+
+    /// Number of days after which documents marked as finished in Plato are removed,
+    /// since they were last opened. Unread and unfinished documents are never removed by it.
+    pub remove_finished: Option<u32>,
+}
+
+impl RetentionSettings {
+    /// Fill in the values not set in `self` with those of `defaults`
+    fn or(self, defaults: &RetentionSettings) -> RetentionSettings {
+        RetentionSettings {
+            keep_entries: self.keep_entries.or(defaults.keep_entries),
+            keep_days: self.keep_days.or(defaults.keep_days),
+            keep_size: self.keep_size.or(defaults.keep_size),
+            remove_finished: self.remove_finished.or(defaults.remove_finished),
         }
     }
+
+    /// Whether entries are kept forever, regardless of their reading state
+    pub fn is_empty(&self) -> bool {
+        self.keep_entries.is_none() && self.keep_days.is_none() && self.keep_size.is_none()
+    }
+}
+
+/// Settings for how the saved entries are reconciled with the files in the directories of the
+/// feeds and with Plato's library, when they have been changed by something other than the hook.
+/// Each is opt-in: by default, only the partially written documents left behind by an interrupted
+/// run are deleted.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ReconcileSettings {
+    /// What to do with the entries whose document is missing from the disk
+    pub missing_files: MissingFiles,
+
+    /// What to do with the EPUBs in the directory of a feed which aren't from any entry
+    pub orphan_files: OrphanFiles,
+
+    /// Whether to add the documents missing from Plato's library back to it
+    pub check_library: bool,
+}
+
+/// What to do with the entries whose document is missing from the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingFiles {
+    /// Leave them alone, as if their document was still there
+    #[default]
+    Keep,
+    /// Download them again, if they are still in their feed
+    Download,
+    /// Forget about them, without downloading them again, and remove them from Plato's library
+    Forget,
+}
+
+/// What to do with the EPUBs in the directory of a feed which aren't from any entry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OrphanFiles {
+    /// Leave them alone
+    #[default]
+    Keep,
+    /// Treat them like the entries of the feed, so they are removed by the [RetentionSettings]
+    Adopt,
+    /// Delete them, and remove them from Plato's library
+    Delete,
+}
+
+/// Settings for how the network is brought up when the hook is started while Plato is offline
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct NetworkSettings {
+    /// Whether to ask Plato to turn Wi-Fi on, rather than asking the user to
+    pub enable_wifi: bool,
+
+    /// Number of seconds to wait for the network to come up.
+    /// `None` waits until the hook is stopped.
+    pub network_timeout: Option<u64>,
+
+    /// Whether to turn Wi-Fi back off once the feeds are updated,
+    /// if it was turned on by [NetworkSettings::enable_wifi]
+    pub disable_wifi: bool,
 }
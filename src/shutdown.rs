@@ -0,0 +1,22 @@
+//! Cancellation of the work in progress when Plato asks the hook to exit.
+
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+/// Listen for `SIGTERM` and `SIGINT`, returning a token which is cancelled once either is received
+pub fn listen() -> Result<CancellationToken> {
+    let token = CancellationToken::new();
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => eprintln!("feed: received SIGTERM"),
+            _ = sigint.recv() => eprintln!("feed: received SIGINT"),
+        }
+        cancel.cancel();
+    });
+
+    Ok(token)
+}